@@ -0,0 +1,30 @@
+use std::env;
+
+// Tells `lib.rs` whether a riscv64 target lacks the D extension, which the
+// riscv64 backend saves fs0-fs11 with. Stable rustc keeps `d` out of
+// `cfg(target_feature)`, so it is told from the ISA string of the target
+// and the target features passed in the flags.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(riscv64_without_d)");
+
+    let target = env::var("TARGET").unwrap();
+    let Some(isa) = target.split('-').next().and_then(|arch| arch.strip_prefix("riscv64")) else {
+        return;
+    };
+    // "g" stands for "imafd", and the RVA2x profiles include D
+    let mut has_d = isa.contains('g') || isa.contains('d') || isa.starts_with("a2");
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    for features in flags.split('\x1f').filter_map(|flag| flag.split_once("target-feature=")) {
+        for feature in features.1.split(',') {
+            match feature {
+                "+d" => has_d = true,
+                "-d" => has_d = false,
+                _ => {}
+            }
+        }
+    }
+    if !has_d {
+        println!("cargo:rustc-cfg=riscv64_without_d");
+    }
+}
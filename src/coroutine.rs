@@ -1,3 +1,9 @@
+//! Coroutines, their stacks and the platform backends switching between them.
+
+#[macro_use]
+mod local;
+
+// the context switch backend, chosen by the `backend-*` features
 #[cfg_attr(target_arch = "x86", path = "coroutine/platform/i686.rs")]
 #[cfg_attr(
     all(target_arch = "x86_64", windows),
@@ -7,55 +13,859 @@
     all(target_arch = "x86_64", not(windows)),
     path = "coroutine/platform/sysv64.rs"
 )]
+#[cfg_attr(target_arch = "aarch64", path = "coroutine/platform/aarch64.rs")]
+#[cfg_attr(target_arch = "riscv64", path = "coroutine/platform/riscv64.rs")]
+#[cfg(all(
+    feature = "backend-asm",
+    not(feature = "backend-ucontext"),
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+mod platform;
+// also where `backend-asm` has no assembly for the target, given std
+#[cfg(all(
+    unix,
+    feature = "std",
+    any(
+        feature = "backend-ucontext",
+        all(
+            feature = "backend-asm",
+            not(any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            ))
+        )
+    )
+))]
+#[path = "coroutine/platform/ucontext.rs"]
 mod platform;
+// neither, lib.rs reports why
+#[cfg(not(any(
+    all(
+        feature = "backend-asm",
+        not(feature = "backend-ucontext"),
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )
+    ),
+    all(
+        unix,
+        feature = "std",
+        any(
+            feature = "backend-ucontext",
+            all(
+                feature = "backend-asm",
+                not(any(
+                    target_arch = "x86",
+                    target_arch = "x86_64",
+                    target_arch = "aarch64",
+                    target_arch = "riscv64"
+                ))
+            )
+        )
+    )
+)))]
+#[path = "coroutine/platform/unsupported.rs"]
+mod platform;
+#[cfg(feature = "scheduler")]
+pub mod channel;
+#[cfg(all(unix, feature = "guard-pages"))]
+mod guard;
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+mod stack;
+#[cfg(feature = "scheduler")]
+pub mod sync;
+#[cfg(all(test, feature = "std"))]
+mod tests;
 
-use std::marker::PhantomData;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::String;
+use core::any::Any;
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+#[cfg(feature = "std")]
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 
-use platform::{resume_coroutine, return_from_coroutine, Context};
+use platform::{swap_context, Context};
+use stack::StackSpace;
 
-pub struct Coroutine<'a> {
-    context: Context,
-    finished: bool,
-    _phantom: PhantomData<&'a dyn FnOnce()>,
+#[cfg(not(feature = "std"))]
+pub use local::assume_single_threaded;
+#[cfg(all(unix, feature = "guard-pages"))]
+pub use stack::MmapStackAllocator;
+#[cfg(feature = "scheduler")]
+pub use scheduler::{sleep, sleep_until, spawn, timeout, Elapsed, JoinHandle, Scheduler};
+pub use stack::{DefaultStackAllocator, HeapStackAllocator, Stack, StackAllocator, StackPool};
+
+/// What a coroutine handed back to its caller from one `resume`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineResult<Y, R> {
+    /// The coroutine suspended itself with a value and can be resumed again.
+    Yielded(Y),
+    /// The coroutine function returned.
+    Complete(R),
+}
+
+/// How `transfer_to` came back to the coroutine which called it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer<I, R> {
+    /// The coroutine was resumed again with this input, while the one it
+    /// transferred to stays suspended.
+    Resumed(I),
+    /// The coroutine it transferred to returned, and control went straight
+    /// back to it.
+    Returned(R),
+}
+
+/// What a coroutine panicked with.
+pub type PanicPayload = Box<dyn Any + Send + 'static>;
+
+/// Why `try_resume` did not hand back a `CoroutineResult`.
+#[derive(Debug)]
+pub enum ResumeError {
+    /// The coroutine panicked during this resume, with this payload.
+    Panicked(PanicPayload),
+    /// The coroutine had already returned.
+    AlreadyFinished,
+    /// The coroutine had panicked during an earlier resume.
+    Poisoned,
+    /// The coroutine is running, so the resume came from within it.
+    Running,
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResumeError::Panicked(_) => f.write_str("coroutine panicked"),
+            ResumeError::AlreadyFinished => f.write_str("coroutine has already finished"),
+            ResumeError::Poisoned => f.write_str("coroutine has panicked before"),
+            ResumeError::Running => f.write_str("coroutine is already running"),
+        }
+    }
+}
+
+impl core::error::Error for ResumeError {}
+
+// Values exchanged through the `val` of `swap_context`. `resume` passes a
+// pointer to one of these into the coroutine, which takes `input` out of it
+// and leaves what it yields in `yielded`, or what it returns in `*returned`
+// or panics with in `panic`.
+#[repr(C)]
+struct Exchange<I, Y> {
+    // whether `I` and `Y` are `()`, first so that it can be read without
+    // knowing them
+    unit: bool,
+    input: Option<I>,
+    yielded: Option<Y>,
+    // `*mut Option<R>`, erased so `Yielder` does not depend on `R`
+    returned: *mut (),
+    panic: Option<PanicPayload>,
+}
+
+local! {
+    // `Exchange` of the innermost `resume` in progress on this thread, null
+    // while a coroutine is being dropped
+    static CURRENT_EXCHANGE: Cell<*mut ()> = const { Cell::new(core::ptr::null_mut()) };
+
+    // name of the innermost running coroutine on this thread
+    static CURRENT_NAME: Cell<*const Option<String>> = const { Cell::new(core::ptr::null()) };
+}
+
+// name of the running coroutine, only valid until it suspends
+fn current_coroutine_name<'a>() -> Option<&'a str> {
+    let name = CURRENT_NAME.get();
+    if name.is_null() {
+        None
+    } else {
+        unsafe { (*name).as_deref() }
+    }
+}
+
+// Resumed with this instead of an `Exchange`, a coroutine unwinds its stack.
+const CANCEL: usize = 0;
+
+// What a finished coroutine switches away with, see `finish_coroutine`.
+const FINISHED: usize = 1;
+
+// Bookkeeping of which context runs and where control goes back to, shared
+// by the backends; they only provide `Context::new`, `swap_context` and a
+// stub running `call_rust_fn` then `finish_coroutine` on a new stack.
+
+impl Context {
+    fn stack_space(&self) -> Option<&StackSpace> {
+        self.stack_space.as_ref()
+    }
+}
+
+local! {
+    // context of the thread, resumer of the outermost coroutine
+    static MAIN_CTX: UnsafeCell<Context> = const { UnsafeCell::new(Context::main()) };
+
+    static CURRENT_CORO_CTX: Cell<*mut Context> = const { Cell::new(core::ptr::null_mut()) };
+}
+
+// stack of the running coroutine, only valid until it suspends
+unsafe fn current_stack<'a>() -> Option<&'a StackSpace> {
+    let current = CURRENT_CORO_CTX.get();
+    if current.is_null() {
+        None
+    } else {
+        (*current).stack_space.as_ref()
+    }
+}
+
+unsafe fn resume_coroutine(context: &mut Context, val: usize) -> usize {
+    let mut resumer = CURRENT_CORO_CTX.get();
+    if resumer.is_null() {
+        resumer = MAIN_CTX.with(UnsafeCell::get);
+    }
+    context.resumer = resumer;
+    context.transferrer = core::ptr::null_mut();
+    let prev = CURRENT_CORO_CTX.replace(context);
+    let ret = swap_context(resumer, context, val);
+    CURRENT_CORO_CTX.set(prev);
+    ret
+}
+
+// Suspends the running coroutine and switches straight to `context`, which
+// suspends to the resumer of the running one, and switches back to it when
+// it finishes.
+unsafe fn transfer_coroutine(context: &mut Context, val: usize) -> usize {
+    let current = CURRENT_CORO_CTX.get();
+    context.resumer = (*current).resumer;
+    context.transferrer = current;
+    CURRENT_CORO_CTX.set(context);
+    swap_context(current, context, val)
+}
+
+unsafe fn return_from_coroutine(ret: usize) -> usize {
+    let current = CURRENT_CORO_CTX.get();
+    swap_context(current, (*current).resumer, ret)
+}
+
+unsafe extern "C" fn call_rust_fn(func: *mut Box<dyn FnOnce(usize)>, val: usize) {
+    let func = Box::from_raw(func);
+    func(val)
+}
+
+unsafe extern "C" fn finish_coroutine() -> ! {
+    let current = CURRENT_CORO_CTX.get();
+    let transferrer = (*current).transferrer;
+    if transferrer.is_null() {
+        return_from_coroutine(FINISHED);
+    } else {
+        (*transferrer).resumer = (*current).resumer;
+        CURRENT_CORO_CTX.set(transferrer);
+        swap_context(current, transferrer, FINISHED);
+    }
+    unreachable!("a finished coroutine was resumed");
+}
+
+/// Bare context of the switch backend chosen by the `backend-*` features,
+/// which `Coroutine` is built on: a stack, a function to start on it, and
+/// where it was last switched away from.
+///
+/// Nothing else is kept track of: to `in_coroutine`, `yield_now` and the
+/// rest of the crate, code running on a `RawContext` is still in whatever
+/// switched to it, see `switch` for what that rules out.
+pub struct RawContext(Context);
+
+impl RawContext {
+    /// Name of the backend: `i686`, `sysv64`, `win64`, `aarch64`, `riscv64`
+    /// or `ucontext`.
+    pub const BACKEND: &'static str = platform::BACKEND;
+
+    /// Context of the code running outside of any `RawContext`, such as
+    /// `main`, to switch away from and later back to.
+    pub const fn main() -> Self {
+        RawContext(Context::main())
+    }
+
+    /// Context starting `func` on `stack` when first switched to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stack` is not aligned to 16 bytes or is smaller than
+    /// `CoroutineBuilder::MIN_STACK_SIZE`. Aborts if `func` returns, it must
+    /// switch away for the last time instead.
+    pub fn new(stack: impl Stack + 'static, func: impl FnOnce() + 'static) -> Self {
+        RawContext(Context::new(StackSpace::provided(stack, false), move |_| {
+            func();
+            panic!("the function of a `RawContext` returned");
+        }))
+    }
+
+    /// Saves where the running code is in `from` and switches to `to`,
+    /// returning once something switches back to `from`.
+    ///
+    /// # Safety
+    ///
+    /// `from` must be the context of the running code, and `to` one created
+    /// by `new` which was never switched to, or one suspended by `switch`.
+    /// Both must stay at the same address until `from` is switched back to,
+    /// and a context must not be dropped while code runs on its stack.
+    ///
+    /// Code running on `to` must not yield or resume coroutines in place of
+    /// one that switched to it: `yield_now` and `Yielder::yield_` would save
+    /// its registers as those of that coroutine. A panic in its function is
+    /// not caught and aborts the process once it reaches the bottom of the
+    /// stack.
+    pub unsafe fn switch(from: *mut RawContext, to: *mut RawContext) {
+        swap_context(core::ptr::addr_of_mut!((*from).0), core::ptr::addr_of_mut!((*to).0), 0);
+    }
+}
+
+// Panic payload of the unwinding started by `CANCEL`.
+#[cfg(feature = "std")]
+struct ForcedUnwind;
+
+// Gives up the running coroutine once it is resumed with `CANCEL`. With std
+// its stack is unwound, without std nothing on it is dropped and it merely
+// reports having finished.
+unsafe fn cancel() -> ! {
+    #[cfg(feature = "std")]
+    resume_unwind(Box::new(ForcedUnwind));
+    #[cfg(not(feature = "std"))]
+    {
+        return_from_coroutine(FINISHED);
+        unreachable!("a cancelled coroutine was resumed");
+    }
+}
+
+// Continues the panic of a coroutine in the code which resumed it.
+fn reraise(payload: PanicPayload) -> ! {
+    #[cfg(feature = "std")]
+    resume_unwind(payload);
+    #[cfg(not(feature = "std"))]
+    {
+        let _ = payload;
+        unreachable!("panics of coroutines are only caught with std");
+    }
 }
 
-impl<'a> Coroutine<'a> {
-    pub fn new(func: impl FnOnce() + 'a) -> Self {
+// Suspends the current coroutine and returns the `val` it is resumed with.
+// Panics while the coroutine is being dropped, which must not be suspended.
+unsafe fn suspend() -> usize {
+    assert!(
+        !CURRENT_EXCHANGE.get().is_null(),
+        "cannot yield from a coroutine being dropped"
+    );
+    let val = return_from_coroutine(0);
+    if val == CANCEL {
+        cancel();
+    }
+    val
+}
+
+/// Handle given to a coroutine function to suspend itself.
+pub struct Yielder<I, Y> {
+    _phantom: PhantomData<*mut Exchange<I, Y>>,
+}
+
+impl<I, Y> Yielder<I, Y> {
+    /// Suspends the coroutine, handing `val` to the caller of `resume`.
+    /// Returns the input of the next `resume`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from another coroutine than the one it was given to,
+    /// such as one resumed by that coroutine, or while the coroutine is being
+    /// dropped.
+    pub fn yield_(&self, val: Y) -> I {
+        self.assert_current();
+        let exchange = CURRENT_EXCHANGE.get() as *mut Exchange<I, Y>;
+        assert!(!exchange.is_null(), "cannot yield from a coroutine being dropped");
+        unsafe {
+            (*exchange).yielded = Some(val);
+            let exchange = suspend() as *mut Exchange<I, Y>;
+            (*exchange).input.take().unwrap()
+        }
+    }
+
+    /// Suspends the coroutine and runs `other` in its place with `input`,
+    /// switching straight to it instead of through the caller of `resume`.
+    /// What `other` yields goes straight to that caller, as if this coroutine
+    /// had yielded it, and the next `resume` continues this coroutine.
+    ///
+    /// Returns `Transfer::Resumed` with the input of that `resume`, or
+    /// `Transfer::Returned` with what `other` returned if it finished first,
+    /// in which case it switched straight back here.
+    ///
+    /// # Panics
+    ///
+    /// Panics like `yield_`, or if `other` has already finished, and
+    /// re-raises the panic if `other` panics.
+    pub fn transfer_to<R>(&self, other: &mut Coroutine<'_, I, Y, R>, input: I) -> Transfer<I, R> {
+        self.assert_current();
+        unsafe { other.transfer_in(input) }
+    }
+
+    fn assert_current(&self) {
+        // the yielder lives on the stack of its coroutine
+        let addr = self as *const Self as usize;
+        assert!(
+            unsafe { current_stack() }.is_some_and(|stack| stack.contains(addr)),
+            "`Yielder` used outside of its coroutine"
+        );
+    }
+}
+
+/// Configuration for a new coroutine, in the manner of `std::thread::Builder`.
+pub struct CoroutineBuilder {
+    name: Option<String>,
+    stack_size: usize,
+    // the `StackPool` of the thread if `None`, only looked up once a stack
+    // is allocated
+    stack_allocator: Option<Rc<dyn StackAllocator>>,
+    measure_stack_usage: bool,
+}
+
+impl CoroutineBuilder {
+    /// Stack size of coroutines unless told otherwise.
+    pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 8;
+    /// Smallest stack size a coroutine is given.
+    pub const MIN_STACK_SIZE: usize = 1024 * 16;
+
+    /// Starts with the default stack size, no name, and stacks from the
+    /// `StackPool` of the current thread.
+    pub fn new() -> Self {
+        CoroutineBuilder {
+            name: None,
+            stack_size: Self::DEFAULT_STACK_SIZE,
+            stack_allocator: None,
+            measure_stack_usage: false,
+        }
+    }
+
+    /// Names the coroutine, which shows up in stack overflow reports.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the size of the coroutine stack in bytes, which is raised to at
+    /// least `MIN_STACK_SIZE`.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size.max(Self::MIN_STACK_SIZE);
+        self
+    }
+
+    /// Allocates the stack with `allocator` instead of the `StackPool` of the
+    /// current thread.
+    pub fn stack_allocator(mut self, allocator: impl StackAllocator + 'static) -> Self {
+        self.stack_allocator = Some(Rc::new(allocator));
+        self
+    }
+
+    /// Fills the stack with a canary pattern when it is allocated, so that
+    /// `Coroutine::peak_stack_usage` can tell how much of it was used. This
+    /// touches every page of the stack up front.
+    pub fn measure_stack_usage(mut self, enable: bool) -> Self {
+        self.measure_stack_usage = enable;
+        self
+    }
+
+    /// Creates the coroutine, see `Coroutine::new`.
+    pub fn build<'a, R>(self, func: impl FnOnce() -> R + 'a) -> Coroutine<'a, (), (), R> {
+        let stack_space = self.allocate_stack();
+        self.spawn_unit(stack_space, func)
+    }
+
+    /// Creates the coroutine, see `Coroutine::with_yielder`.
+    pub fn build_with_yielder<'a, I, Y, R>(
+        self,
+        func: impl FnOnce(&Yielder<I, Y>, I) -> R + 'a,
+    ) -> Coroutine<'a, I, Y, R> {
+        let stack_space = self.allocate_stack();
+        self.spawn(stack_space, func)
+    }
+
+    /// Creates the coroutine running on `stack` rather than on one from the
+    /// stack allocator, so the stack size and allocator are not used and the
+    /// `StackPool` of the thread is not touched. A few small allocations
+    /// remain: `func` and `stack` are boxed, and so is the name if any.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stack` is not aligned to 16 bytes, or smaller than
+    /// `MIN_STACK_SIZE`.
+    pub fn build_on<'a, R>(
+        self,
+        stack: impl Stack + 'a,
+        func: impl FnOnce() -> R + 'a,
+    ) -> Coroutine<'a, (), (), R> {
+        let stack_space = StackSpace::provided(stack, self.measure_stack_usage);
+        self.spawn_unit(stack_space, func)
+    }
+
+    /// Like `build_on`, for a coroutine created as by `build_with_yielder`.
+    pub fn build_with_yielder_on<'a, I, Y, R>(
+        self,
+        stack: impl Stack + 'a,
+        func: impl FnOnce(&Yielder<I, Y>, I) -> R + 'a,
+    ) -> Coroutine<'a, I, Y, R> {
+        let stack_space = StackSpace::provided(stack, self.measure_stack_usage);
+        self.spawn(stack_space, func)
+    }
+
+    fn allocate_stack(&self) -> StackSpace {
+        let allocator = match &self.stack_allocator {
+            Some(allocator) => allocator.clone(),
+            None => StackPool::current(),
+        };
+        StackSpace::new(self.stack_size, allocator, self.measure_stack_usage)
+    }
+
+    fn spawn_unit<'a, R>(
+        self,
+        stack_space: StackSpace,
+        func: impl FnOnce() -> R + 'a,
+    ) -> Coroutine<'a, (), (), R> {
+        let mut coro = self.spawn(stack_space, move |_: &Yielder<(), ()>, ()| func());
+        coro.yield_now_val = Some(|| ());
+        coro
+    }
+
+    fn spawn<'a, I, Y, R>(
+        self,
+        stack_space: StackSpace,
+        func: impl FnOnce(&Yielder<I, Y>, I) -> R + 'a,
+    ) -> Coroutine<'a, I, Y, R> {
+        let context = Context::new(stack_space, move |val| unsafe {
+            if val == CANCEL {
+                // dropped before it was ever resumed
+                return;
+            }
+            let yielder = Yielder {
+                _phantom: PhantomData,
+            };
+            let run = || {
+                let input = (*(val as *mut Exchange<I, Y>)).input.take().unwrap();
+                func(&yielder, input)
+            };
+            // a panic must not unwind past the bottom of the coroutine stack,
+            // catch it here and hand it to `resume` instead
+            #[cfg(feature = "std")]
+            let ret = catch_unwind(AssertUnwindSafe(run));
+            #[cfg(not(feature = "std"))]
+            let ret = Ok::<R, PanicPayload>(run());
+            // the coroutine may have been resumed by another `resume` since it
+            // started, so look up the current exchange rather than `val`
+            let exchange = CURRENT_EXCHANGE.get() as *mut Exchange<I, Y>;
+            if exchange.is_null() {
+                // unwound by `drop`, nobody is waiting for the result
+                return;
+            }
+            match ret {
+                Ok(ret) => *((*exchange).returned as *mut Option<R>) = Some(ret),
+                Err(payload) => (*exchange).panic = Some(payload),
+            }
+        });
         Coroutine {
-            context: Context::new(func),
+            context,
+            name: self.name,
             finished: false,
+            panicked: false,
+            running: false,
+            yield_now_val: None,
             _phantom: PhantomData,
         }
     }
+}
+
+impl Default for CoroutineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A function running on a stack of its own, which can suspend itself and
+/// be resumed where it left off.
+///
+/// It is resumed with `I`, hands back `Y` whenever it yields and `R` when
+/// it returns. Borrows of lifetime `'a` may be alive on its stack.
+pub struct Coroutine<'a, I = (), Y = (), R = ()> {
+    context: Context,
+    name: Option<String>,
+    finished: bool,
+    panicked: bool,
+    // set while switched in, so that a resume from within can be refused
+    running: bool,
+    // what a bare `yield_now` yields, only set for coroutines created without
+    // a `Yielder`, which exchange `()`
+    yield_now_val: Option<fn() -> Y>,
+    _phantom: PhantomData<&'a dyn FnOnce(I) -> CoroutineResult<Y, R>>,
+}
+
+impl Coroutine<'_> {
+    /// The coroutine running on this thread, or `None` outside of any.
+    pub fn current() -> Option<CurrentCoroutine> {
+        let stack = unsafe { current_stack() }?;
+        let exchange = CURRENT_EXCHANGE.get();
+        Some(CurrentCoroutine {
+            name: current_coroutine_name().map(String::from),
+            stack_size: stack.size(),
+            can_yield_now: !exchange.is_null() && unsafe { *(exchange as *const bool) },
+        })
+    }
+}
+
+impl<'a, R> Coroutine<'a, (), (), R> {
+    /// Creates a coroutine with the default configuration of
+    /// `CoroutineBuilder`. It suspends itself with `yield_now`, and what
+    /// `func` returns is handed to the last `resume`.
+    pub fn new(func: impl FnOnce() -> R + 'a) -> Self {
+        CoroutineBuilder::new().build(func)
+    }
+
+    /// Creates a coroutine running on `stack`, see
+    /// `CoroutineBuilder::build_on`. No stack is allocated, but `func` and
+    /// `stack` are still boxed on the heap.
+    pub fn with_stack(stack: &'a mut [u8], func: impl FnOnce() -> R + 'a) -> Self {
+        CoroutineBuilder::new().build_on(stack, func)
+    }
+
+    /// Suspends the running coroutine and runs this one in its place, like
+    /// `Yielder::transfer_to` does for coroutines with a `Yielder`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from a coroutine created by `Coroutine::new` or
+    /// `CoroutineBuilder::build`, or if this one has already finished, and
+    /// re-raises the panic if this one panics.
+    pub fn transfer_to(&mut self, input: ()) -> Transfer<(), R> {
+        let exchange = CURRENT_EXCHANGE.get();
+        assert!(
+            !exchange.is_null() && unsafe { *(exchange as *const bool) },
+            "`Coroutine::transfer_to` called outside of a coroutine created without a `Yielder`"
+        );
+        unsafe { self.transfer_in(input) }
+    }
+}
+
+impl<'a, I, Y, R> Coroutine<'a, I, Y, R> {
+    /// Creates a coroutine which receives a `Yielder` and the input of the
+    /// first `resume`, with the default configuration of `CoroutineBuilder`.
+    pub fn with_yielder(func: impl FnOnce(&Yielder<I, Y>, I) -> R + 'a) -> Self {
+        CoroutineBuilder::new().build_with_yielder(func)
+    }
+
+    /// Name given by `CoroutineBuilder::name`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Bytes of stack in use when the coroutine last suspended, 0 once it has
+    /// finished.
+    pub fn stack_usage(&self) -> usize {
+        match self.context.stack_space() {
+            Some(stack) if !self.is_finished() => stack.top() - self.context.stack_pointer(),
+            _ => 0,
+        }
+    }
+
+    /// Most bytes of stack the coroutine has used so far, if it was built
+    /// with `CoroutineBuilder::measure_stack_usage`.
+    pub fn peak_stack_usage(&self) -> Option<usize> {
+        self.context.stack_space()?.peak_usage()
+    }
 
+    /// Whether the coroutine has returned or panicked.
     pub fn is_finished(&self) -> bool {
         self.finished
     }
 
-    pub fn resume(&mut self) {
+    /// Whether the coroutine finished by panicking.
+    pub fn is_panicked(&self) -> bool {
+        self.panicked
+    }
+
+    /// Runs the coroutine until it yields or returns.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coroutine cannot be resumed, see `ResumeError`, and
+    /// re-raises the panic if the coroutine panics.
+    pub fn resume(&mut self, input: I) -> CoroutineResult<Y, R> {
+        match self.try_resume(input) {
+            Ok(result) => result,
+            Err(ResumeError::Panicked(payload)) => reraise(payload),
+            Err(err) => panic!("cannot resume coroutine: {}", err),
+        }
+    }
+
+    /// Like `resume`, but returns why the coroutine could not be resumed, or
+    /// what it panicked with, as `Err` instead of panicking.
+    pub fn try_resume(&mut self, input: I) -> Result<CoroutineResult<Y, R>, ResumeError> {
+        if self.running {
+            return Err(ResumeError::Running);
+        } else if self.panicked {
+            return Err(ResumeError::Poisoned);
+        } else if self.finished {
+            return Err(ResumeError::AlreadyFinished);
+        }
+
+        let mut returned: Option<R> = None;
+        let mut exchange = Exchange {
+            unit: self.yield_now_val.is_some(),
+            input: Some(input),
+            yielded: None,
+            returned: &mut returned as *mut Option<R> as *mut (),
+            panic: None,
+        };
+        unsafe {
+            self.switch_in(&mut exchange as *mut Exchange<I, Y> as *mut ());
+        }
+
+        if let Some(payload) = exchange.panic {
+            self.panicked = true;
+            Err(ResumeError::Panicked(payload))
+        } else if self.finished {
+            Ok(CoroutineResult::Complete(returned.unwrap()))
+        } else {
+            // `yield_now` only suspends coroutines with a `yield_now_val`
+            let yielded = exchange.yielded.or_else(|| self.yield_now_val.map(|val| val()));
+            Ok(CoroutineResult::Yielded(yielded.unwrap()))
+        }
+    }
+
+    // Runs the coroutine until it suspends or finishes, making it the current
+    // one of the thread meanwhile. A null `exchange` makes it unwind.
+    unsafe fn switch_in(&mut self, exchange: *mut ()) {
+        let prev_exchange = CURRENT_EXCHANGE.replace(exchange);
+        let prev_name = CURRENT_NAME.replace(&self.name);
+        let val = if exchange.is_null() { CANCEL } else { exchange as usize };
+        self.running = true;
+        self.finished = resume_coroutine(&mut self.context, val) == FINISHED;
+        self.running = false;
+        CURRENT_NAME.set(prev_name);
+        CURRENT_EXCHANGE.set(prev_exchange);
+    }
+
+    // Suspends the running coroutine, which exchanges `I` and `Y` like this
+    // one, and switches to this one in its place. See `Yielder::transfer_to`.
+    unsafe fn transfer_in(&mut self, input: I) -> Transfer<I, R> {
+        assert!(!self.is_finished(), "cannot transfer to a finished coroutine");
+        let exchange = CURRENT_EXCHANGE.get() as *mut Exchange<I, Y>;
+        assert!(!exchange.is_null(), "cannot transfer from a coroutine being dropped");
+
+        // this one returns to us rather than to the caller of `resume`
+        let mut returned: Option<R> = None;
+        let prev_returned = core::mem::replace(
+            &mut (*exchange).returned,
+            &mut returned as *mut Option<R> as *mut (),
+        );
+        (*exchange).input = Some(input);
+        let prev_name = CURRENT_NAME.replace(&self.name);
+        self.running = true;
+        let val = transfer_coroutine(&mut self.context, exchange as usize);
+        self.running = false;
+        match val {
+            CANCEL => cancel(),
+            FINISHED => {
+                // still within the same `resume`, so `exchange` is alive
+                CURRENT_NAME.set(prev_name);
+                (*exchange).returned = prev_returned;
+                self.finished = true;
+                if let Some(payload) = (*exchange).panic.take() {
+                    self.panicked = true;
+                    reraise(payload);
+                }
+                Transfer::Returned(returned.unwrap())
+            }
+            val => Transfer::Resumed((*(val as *mut Exchange<I, Y>)).input.take().unwrap()),
+        }
+    }
+
+    /// Drops the coroutine without unwinding its stack, so nothing still
+    /// alive on it is dropped.
+    pub fn leak(self) {
+        let mut coro = ManuallyDrop::new(self);
+        unsafe { core::ptr::drop_in_place(&mut coro.context) }
+    }
+}
+
+impl<'a, I, Y, R> Drop for Coroutine<'a, I, Y, R> {
+    /// Unwinds the stack of a suspended coroutine, running the destructors of
+    /// everything still alive on it, before the stack is freed. When the
+    /// current thread is already panicking the stack is leaked as by `leak`
+    /// instead, since a second unwind cannot be started.
+    ///
+    /// Without std, nothing on the stack is dropped before it is freed.
+    fn drop(&mut self) {
         if self.is_finished() {
             return;
         }
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            return;
+        }
 
         unsafe {
-            self.finished = resume_coroutine(self, 0) != 0;
+            self.switch_in(core::ptr::null_mut());
         }
+        assert!(self.finished, "coroutine suspended itself while being dropped");
     }
 }
 
-pub fn yield_now() {
-    unsafe { return_from_coroutine(0); }
+/// What `Coroutine::current` tells about the running coroutine, as of the
+/// call.
+#[derive(Debug, Clone)]
+pub struct CurrentCoroutine {
+    name: Option<String>,
+    stack_size: usize,
+    can_yield_now: bool,
 }
 
-pub fn schedule(coros: &mut [Coroutine]) {
-    let mut all_finished = false;
-    while !all_finished {
-        all_finished = true;
-        for co in coros.iter_mut() {
-            if !co.is_finished() {
-                all_finished = false;
-                co.resume();
-            }
-        }
+impl CurrentCoroutine {
+    /// Name given by `CoroutineBuilder::name`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Bytes of stack the coroutine runs on.
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
+
+    /// Whether `yield_now` can suspend the coroutine, which is when it was
+    /// created without a `Yielder` and is not being dropped.
+    pub fn can_yield_now(&self) -> bool {
+        self.can_yield_now
     }
 }
+
+/// Whether this is called from within a coroutine.
+pub fn in_coroutine() -> bool {
+    unsafe { current_stack() }.is_some()
+}
+
+/// Suspends the running coroutine, which must have been created without a
+/// `Yielder`, until it is resumed again.
+///
+/// # Panics
+///
+/// Panics if not called from within a coroutine, if the coroutine was
+/// created with a `Yielder`, or while it is being dropped.
+pub fn yield_now() {
+    assert!(in_coroutine(), "`yield_now` called outside of a coroutine");
+    let exchange = CURRENT_EXCHANGE.get();
+    assert!(
+        exchange.is_null() || unsafe { *(exchange as *const bool) },
+        "`yield_now` can only suspend a coroutine created without a `Yielder`, use `Yielder::yield_`"
+    );
+    unsafe { suspend(); }
+}
@@ -0,0 +1,368 @@
+//! Channels between the tasks of a scheduler, in the manner of
+//! `std::sync::mpsc`.
+//!
+//! Receiving from an empty channel, or sending to a full one, parks the task
+//! until its peer makes progress, so the other tasks keep running meanwhile.
+//! Blocking calls can therefore only be made from a task, the `try_` ones
+//! from anywhere on the thread.
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::cell::{Cell, RefCell};
+
+pub use std::sync::mpsc::{RecvError, SendError, TryRecvError, TrySendError};
+
+use super::scheduler::WaitQueue;
+
+// State of a channel, shared by its ends.
+struct Shared<T> {
+    buffer: RefCell<VecDeque<T>>,
+    // most messages buffered, `None` if unbounded, 0 if each `send` waits for
+    // its message to be received
+    bound: Option<usize>,
+    senders: Cell<usize>,
+    receiver: Cell<bool>,
+    // messages sent and received so far, for a rendezvous `send` to tell when
+    // its message has been taken
+    sent: Cell<u64>,
+    received: Cell<u64>,
+    // tasks waiting for a message
+    receiving: WaitQueue,
+    // tasks waiting for room in the buffer, or for their message to be taken
+    sending: WaitQueue,
+}
+
+impl<T> Shared<T> {
+    fn new(bound: Option<usize>) -> Rc<Self> {
+        Rc::new(Shared {
+            buffer: RefCell::new(VecDeque::new()),
+            bound,
+            senders: Cell::new(1),
+            receiver: Cell::new(true),
+            sent: Cell::new(0),
+            received: Cell::new(0),
+            receiving: WaitQueue::default(),
+            sending: WaitQueue::default(),
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        match self.bound {
+            Some(bound) => self.buffer.borrow().len() >= bound.max(1),
+            None => false,
+        }
+    }
+
+    fn push(&self, msg: T) -> u64 {
+        self.buffer.borrow_mut().push_back(msg);
+        let seq = self.sent.get();
+        self.sent.set(seq + 1);
+        self.receiving.notify_one();
+        seq
+    }
+
+    fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        loop {
+            if !self.receiver.get() {
+                return Err(SendError(msg));
+            }
+            if !self.is_full() {
+                break;
+            }
+            self.sending.wait();
+        }
+        let seq = self.push(msg);
+        if self.bound == Some(0) {
+            while self.received.get() <= seq {
+                if !self.receiver.get() {
+                    // nothing else can be buffered meanwhile, it is still ours
+                    let msg = self.buffer.borrow_mut().pop_back().unwrap();
+                    return Err(SendError(msg));
+                }
+                self.sending.wait();
+            }
+        }
+        Ok(())
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let msg = self.buffer.borrow_mut().pop_front();
+        match msg {
+            Some(msg) => {
+                self.received.set(self.received.get() + 1);
+                self.sending.notify_all();
+                Ok(msg)
+            }
+            None if self.senders.get() == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(msg) => return Ok(msg),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => self.receiving.wait(),
+            }
+        }
+    }
+}
+
+/// Creates a channel with an unbounded buffer, so that sending never
+/// blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Shared::new(None);
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Creates a channel buffering at most `bound` messages, beyond which
+/// sending blocks. With a `bound` of 0 every `send` blocks until its message
+/// is received.
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    let shared = Shared::new(Some(bound));
+    (
+        SyncSender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Sending end of a `channel`, which can be cloned to send from several
+/// tasks.
+pub struct Sender<T> {
+    shared: Rc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `msg` without blocking. Fails with `msg` once the `Receiver` has
+    /// been dropped.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        self.shared.send(msg)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.set(self.shared.senders.get() + 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+/// Sending end of a `sync_channel`, which can be cloned to send from several
+/// tasks.
+pub struct SyncSender<T> {
+    shared: Rc<Shared<T>>,
+}
+
+impl<T> SyncSender<T> {
+    /// Sends `msg`, parking the running task while the buffer is full, or
+    /// until `msg` is received if the bound is 0. Fails with `msg` once the
+    /// `Receiver` has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        self.shared.send(msg)
+    }
+
+    /// Sends `msg` if there is room for it in the buffer, or with a bound of
+    /// 0 if a task is waiting to receive it.
+    pub fn try_send(&self, msg: T) -> Result<(), TrySendError<T>> {
+        let shared = &self.shared;
+        if !shared.receiver.get() {
+            return Err(TrySendError::Disconnected(msg));
+        }
+        let room = match shared.bound {
+            Some(0) => shared.buffer.borrow().is_empty() && shared.receiving.has_waiters(),
+            _ => !shared.is_full(),
+        };
+        if !room {
+            return Err(TrySendError::Full(msg));
+        }
+        shared.push(msg);
+        Ok(())
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.set(self.shared.senders.get() + 1);
+        SyncSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+fn drop_sender<T>(shared: &Shared<T>) {
+    shared.senders.set(shared.senders.get() - 1);
+    if shared.senders.get() == 0 {
+        shared.receiving.notify_all();
+    }
+}
+
+/// Receiving end of a `channel` or a `sync_channel`.
+pub struct Receiver<T> {
+    shared: Rc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the next message, parking the running task until there is one.
+    /// Fails once the buffer is empty and every sender has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.shared.recv()
+    }
+
+    /// Takes the next message if there is one.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.shared.try_recv()
+    }
+
+    /// Iterates over the messages as received by `recv`, until every sender
+    /// has been dropped.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver.set(false);
+        self.shared.sending.notify_all();
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// Iterator returned by `Receiver::iter`.
+pub struct Iter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+// State of a `oneshot`, shared by its ends.
+struct Oneshot<T> {
+    value: RefCell<Option<T>>,
+    // the sender was dropped without sending
+    closed: Cell<bool>,
+    receiver: Cell<bool>,
+    receiving: WaitQueue,
+}
+
+/// Creates a channel for a single message.
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let shared = Rc::new(Oneshot {
+        value: RefCell::new(None),
+        closed: Cell::new(false),
+        receiver: Cell::new(true),
+        receiving: WaitQueue::default(),
+    });
+    (
+        OneshotSender {
+            shared: shared.clone(),
+        },
+        OneshotReceiver { shared },
+    )
+}
+
+/// Sending end of a `oneshot`.
+pub struct OneshotSender<T> {
+    shared: Rc<Oneshot<T>>,
+}
+
+impl<T> OneshotSender<T> {
+    /// Sends `msg` without blocking. Fails with `msg` if the receiver has
+    /// been dropped.
+    pub fn send(self, msg: T) -> Result<(), SendError<T>> {
+        if !self.shared.receiver.get() {
+            return Err(SendError(msg));
+        }
+        *self.shared.value.borrow_mut() = Some(msg);
+        Ok(())
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        // whether or not it sent, there is nothing more to wait for
+        self.shared.closed.set(true);
+        self.shared.receiving.notify_all();
+    }
+}
+
+/// Receiving end of a `oneshot`.
+pub struct OneshotReceiver<T> {
+    shared: Rc<Oneshot<T>>,
+}
+
+impl<T> OneshotReceiver<T> {
+    /// Takes the message, parking the running task until it is sent. Fails if
+    /// the sender is dropped without sending.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn recv(mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(msg) => return Ok(msg),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => self.shared.receiving.wait(),
+            }
+        }
+    }
+
+    /// Takes the message if it has been sent.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.shared.value.borrow_mut().take() {
+            Some(msg) => Ok(msg),
+            None if self.shared.closed.get() => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver.set(false);
+    }
+}
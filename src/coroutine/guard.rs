@@ -0,0 +1,166 @@
+// Reporting of coroutine stack overflows: a SIGSEGV/SIGBUS handler, running on
+// an alternate signal stack, that recognises faults on the guard page of the
+// running coroutine's stack.
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+use libc::{c_int, c_void, sigaction, siginfo_t};
+
+use super::{current_coroutine_name, current_stack};
+
+const SIGNALS: [c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+// handlers installed before ours, indexed like `SIGNALS`
+static mut PREV_ACTIONS: [MaybeUninit<sigaction>; 2] = [MaybeUninit::uninit(); 2];
+
+pub fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            PAGE_SIZE.store(size, Ordering::Relaxed);
+            size
+        }
+        size => size,
+    }
+}
+
+// Installs the overflow handler for the process, and an alternate signal
+// stack for the current thread if it has none.
+pub fn install_overflow_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        for (index, &signal) in SIGNALS.iter().enumerate() {
+            let mut action: sigaction = core::mem::zeroed();
+            action.sa_sigaction = handle_overflow as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let prev = (*core::ptr::addr_of_mut!(PREV_ACTIONS))[index].as_mut_ptr();
+            libc::sigaction(signal, &action, prev);
+        }
+    });
+    let _ = ALT_STACK.try_with(|_| {});
+}
+
+// Alternate signal stack set up by us, taken down when the thread exits.
+struct AltStack {
+    base: *mut c_void,
+}
+
+impl AltStack {
+    fn new() -> AltStack {
+        unsafe {
+            let mut current: libc::stack_t = core::mem::zeroed();
+            libc::sigaltstack(core::ptr::null(), &mut current);
+            if current.ss_flags & libc::SS_DISABLE == 0 {
+                // the thread already has one, e.g. from std
+                return AltStack {
+                    base: core::ptr::null_mut(),
+                };
+            }
+
+            let base = libc::mmap(
+                core::ptr::null_mut(),
+                ALT_STACK_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return AltStack {
+                    base: core::ptr::null_mut(),
+                };
+            }
+            let stack = libc::stack_t {
+                ss_sp: base,
+                ss_flags: 0,
+                ss_size: ALT_STACK_SIZE,
+            };
+            libc::sigaltstack(&stack, core::ptr::null_mut());
+            AltStack { base }
+        }
+    }
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        if self.base.is_null() {
+            return;
+        }
+        unsafe {
+            let disable = libc::stack_t {
+                ss_sp: core::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: ALT_STACK_SIZE,
+            };
+            libc::sigaltstack(&disable, core::ptr::null_mut());
+            libc::munmap(self.base, ALT_STACK_SIZE);
+        }
+    }
+}
+
+std::thread_local! {
+    static ALT_STACK: AltStack = AltStack::new();
+}
+
+unsafe extern "C" fn handle_overflow(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let addr = (*info).si_addr() as usize;
+    if let Some(stack) = current_stack() {
+        if stack.guard_contains(addr) {
+            let name = current_coroutine_name().unwrap_or("<unnamed>");
+            report_overflow(name, stack.size());
+            libc::abort();
+        }
+    }
+
+    // not ours, hand it to the handler installed before
+    let index = SIGNALS.iter().position(|&s| s == signal).unwrap_or(0);
+    let prev = (*core::ptr::addr_of!(PREV_ACTIONS))[index].assume_init_ref();
+    match prev.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // the fault happens again on return, then with the default action
+            libc::signal(signal, libc::SIG_DFL);
+        }
+        handler if prev.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: unsafe extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                core::mem::transmute(handler);
+            handler(signal, info, context);
+        }
+        handler => {
+            let handler: unsafe extern "C" fn(c_int) = core::mem::transmute(handler);
+            handler(signal);
+        }
+    }
+}
+
+// writes "coroutine '<name>' overflowed its <size>-byte stack" to stderr
+// without allocating
+unsafe fn report_overflow(name: &str, size: usize) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    let mut rest = size;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    let parts: [&[u8]; 5] = [
+        b"coroutine '",
+        name.as_bytes(),
+        b"' overflowed its ",
+        &digits[start..],
+        b"-byte stack\n",
+    ];
+    for part in parts {
+        libc::write(libc::STDERR_FILENO, part.as_ptr() as *const c_void, part.len());
+    }
+}
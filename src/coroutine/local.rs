@@ -0,0 +1,80 @@
+// Per-thread state of the crate. Thread-locals with std; without it there is
+// no telling threads apart, so they are plain statics and coroutines must be
+// used from a single thread only, as promised by `assume_single_threaded`.
+
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "std")]
+macro_rules! local {
+    ($($tt:tt)*) => {
+        std::thread_local!($($tt)*);
+    };
+}
+
+#[cfg(not(feature = "std"))]
+macro_rules! local {
+    ($(#[$attr:meta])* static $name:ident: $t:ty = const { $init:expr }; $($rest:tt)*) => {
+        $(#[$attr])*
+        static $name: $crate::coroutine::local::Local<$t> =
+            $crate::coroutine::local::Local::new($init);
+        local!($($rest)*);
+    };
+    () => {};
+}
+
+// Stands in for `std::thread::LocalKey`.
+#[cfg(not(feature = "std"))]
+pub struct Local<T>(T);
+
+// only ever touched once `assume_single_threaded` was called, by its
+// caller's promise from the one thread
+#[cfg(not(feature = "std"))]
+unsafe impl<T> Sync for Local<T> {}
+
+#[cfg(not(feature = "std"))]
+static SINGLE_THREADED: AtomicBool = AtomicBool::new(false);
+
+/// Lets coroutines be used without std, where the crate keeps the running
+/// coroutine in plain statics instead of thread-locals. Until it is called,
+/// creating or resuming a coroutine panics.
+///
+/// # Safety
+///
+/// Coroutines must only ever be used from a single thread, the same one
+/// for the whole life of the program, and never from an interrupt handler
+/// which can preempt them.
+#[cfg(not(feature = "std"))]
+pub unsafe fn assume_single_threaded() {
+    SINGLE_THREADED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Local<T> {
+    pub const fn new(value: T) -> Self {
+        Local(value)
+    }
+
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        assert!(
+            SINGLE_THREADED.load(Ordering::Relaxed),
+            "coroutines without std need `assume_single_threaded` to be called first"
+        );
+        f(&self.0)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: Copy> Local<core::cell::Cell<T>> {
+    pub fn get(&'static self) -> T {
+        self.with(core::cell::Cell::get)
+    }
+
+    pub fn set(&'static self, value: T) {
+        self.with(|cell| cell.set(value))
+    }
+
+    pub fn replace(&'static self, value: T) -> T {
+        self.with(|cell| cell.replace(value))
+    }
+}
@@ -0,0 +1,122 @@
+use core::arch::global_asm;
+use alloc::boxed::Box;
+
+use super::stack::StackSpace;
+
+pub const BACKEND: &str = "aarch64";
+
+type Address = usize;
+
+#[repr(C)]
+pub struct Context {
+    resume_addr: Address,
+    resume_sp: Address,
+    pub(super) stack_space: Option<StackSpace>,
+    // context which resumed this one and gets control back when it suspends
+    pub(super) resumer: *mut Context,
+    // context which transferred to this one and gets control back when it
+    // finishes, null if it was resumed
+    pub(super) transferrer: *mut Context,
+}
+
+impl Context {
+    pub fn new(mut stack_space: StackSpace, func: impl FnOnce(usize)) -> Context {
+        stack_space.prepare();
+        let size = stack_space.size();
+        let func = Box::new(Box::new(func) as Box<dyn FnOnce(usize)>);
+        let func = Box::into_raw(func);
+        // we use br to goto coro_stub, sp must stay 16-byte aligned
+        let stack_top = unsafe { stack_space.address.offset(size as isize - 16) };
+        unsafe {
+            (stack_top as *mut usize).write(func as _);
+        }
+        Context {
+            resume_addr: coro_stub as *const () as _,
+            resume_sp: stack_top as _,
+            stack_space: Some(stack_space),
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    // context of a thread, only ever switched away from and back to
+    pub const fn main() -> Context {
+        Context {
+            resume_addr: 0,
+            resume_sp: 0,
+            stack_space: None,
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    // stack pointer saved when the context was last switched away from
+    pub fn stack_pointer(&self) -> usize {
+        self.resume_sp
+    }
+}
+
+#[allow(improper_ctypes)]
+extern "C" {
+    fn coro_stub();
+    pub fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize;
+}
+
+// coro_stub
+// assume when start, function ptr is in [sp] and the resume value is in x0
+global_asm!(
+    ".global {0}",
+    "{0}:",
+    "mov x1, x0",
+    "ldr x0, [sp]",
+    "mov x29, xzr",            // end of the frame pointer chain
+    "bl {call_rust_fn}",       // call_rust_fn([sp], x0)
+    "bl {finish_coroutine}",   // finish_coroutine()
+    sym coro_stub,
+    call_rust_fn = sym super::call_rust_fn,
+    finish_coroutine = sym super::finish_coroutine,
+);
+
+// swap_context
+// current: x0
+// next: x1
+// val: x2
+// -> x0
+global_asm!(
+    ".global {0}",
+    "{0}:",
+    "sub sp, sp, 160",
+    "stp x19, x20, [sp, 0]",
+    "stp x21, x22, [sp, 16]",
+    "stp x23, x24, [sp, 32]",
+    "stp x25, x26, [sp, 48]",
+    "stp x27, x28, [sp, 64]",
+    "stp x29, x30, [sp, 80]",
+    "stp d8, d9, [sp, 96]",
+    "stp d10, d11, [sp, 112]",
+    "stp d12, d13, [sp, 128]",
+    "stp d14, d15, [sp, 144]",
+    "mov x9, sp",
+    "str x9, [x0, 8]",                  // current.resume_sp = sp
+    "adr x9, co_ret_addr",
+    "str x9, [x0]",                     // current.resume_addr = &&co_ret_addr
+    "ldr x9, [x1, 8]",
+    "mov sp, x9",                       // sp = next.resume_sp
+    "mov x0, x2",                       // x0 = val
+    "ldr x9, [x1]",
+    "br x9",                            // goto next.resume_addr
+    "co_ret_addr:",
+    "ldp d14, d15, [sp, 144]",
+    "ldp d12, d13, [sp, 128]",
+    "ldp d10, d11, [sp, 112]",
+    "ldp d8, d9, [sp, 96]",
+    "ldp x29, x30, [sp, 80]",
+    "ldp x27, x28, [sp, 64]",
+    "ldp x25, x26, [sp, 48]",
+    "ldp x23, x24, [sp, 32]",
+    "ldp x21, x22, [sp, 16]",
+    "ldp x19, x20, [sp, 0]",
+    "add sp, sp, 160",
+    "ret",                              // return x0
+    sym swap_context,
+);
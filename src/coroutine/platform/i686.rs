@@ -1,110 +1,81 @@
 use core::arch::global_asm;
+use alloc::boxed::Box;
 
-extern crate alloc;
+use super::stack::StackSpace;
 
-use super::Coroutine;
+pub const BACKEND: &str = "i686";
 
 type Address = usize;
 
-struct StackSpace {
-    pub address: *mut u8,
-    layout: core::alloc::Layout,
-}
-
-impl StackSpace {
-    // size: szie in byte
-    pub unsafe fn new(size: usize) -> Self {
-        let layout = core::alloc::Layout::from_size_align(size, 16).unwrap();
-        let address = alloc::alloc::alloc(layout);
-        StackSpace { address, layout }
-    }
-}
-
-impl Drop for StackSpace {
-    fn drop(&mut self) {
-        unsafe {
-            alloc::alloc::dealloc(self.address, self.layout);
-        }
-    }
-}
-
 #[repr(C)]
 pub struct Context {
     resume_addr: Address,
     resume_esp: Address,
-    stack_space: Option<StackSpace>,
+    pub(super) stack_space: Option<StackSpace>,
+    // context which resumed this one and gets control back when it suspends
+    pub(super) resumer: *mut Context,
+    // context which transferred to this one and gets control back when it
+    // finishes, null if it was resumed
+    pub(super) transferrer: *mut Context,
 }
 
 impl Context {
-    pub fn new(func: impl FnOnce()) -> Context {
-        assert!(cfg!(target_arch = "x86"));
-        const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 8;
-        let size = DEFAULT_STACK_SIZE;
-        let func = Box::new(Box::new(func) as Box<dyn FnOnce()>);
+    pub fn new(mut stack_space: StackSpace, func: impl FnOnce(usize)) -> Context {
+        stack_space.prepare();
+        let size = stack_space.size();
+        let func = Box::new(Box::new(func) as Box<dyn FnOnce(usize)>);
         let func = Box::into_raw(func);
-        let stack_space = unsafe { StackSpace::new(size) };
         // we use jmp to goto coro_stub
         let stack_top = unsafe { stack_space.address.offset(size as isize - 4) };
         unsafe {
             (stack_top as *mut usize).write(func as _);
         }
         Context {
-            resume_addr: coro_stub as _,
+            resume_addr: coro_stub as *const () as _,
             resume_esp: stack_top as _,
             stack_space: Some(stack_space),
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
         }
     }
-}
-
-// context of main thread
-static mut MAIN_CTX: Context = Context {
-    resume_addr: 0,
-    resume_esp: 0,
-    stack_space: None,
-};
-
-static mut CURRENT_CORO_CTX: *mut Context = core::ptr::null_mut();
 
-pub unsafe fn resume_coroutine(coro: &mut Coroutine, val: usize) -> usize {
-    CURRENT_CORO_CTX = &mut coro.context;
-    swap_context(&mut MAIN_CTX, CURRENT_CORO_CTX, val)
-}
+    // context of a thread, only ever switched away from and back to
+    pub const fn main() -> Context {
+        Context {
+            resume_addr: 0,
+            resume_esp: 0,
+            stack_space: None,
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
 
-pub unsafe fn return_from_coroutine(ret: usize) -> usize {
-    swap_context(CURRENT_CORO_CTX, &mut MAIN_CTX, ret)
+    // stack pointer saved when the context was last switched away from
+    pub fn stack_pointer(&self) -> usize {
+        self.resume_esp
+    }
 }
 
 #[allow(improper_ctypes)]
 extern "cdecl" {
     fn coro_stub();
-    fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize;
-}
-
-unsafe extern "C" fn call_rust_fn(func: *mut Box<dyn FnOnce()>) {
-    let func = Box::from_raw(func);
-    func()
+    pub fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize;
 }
 
 // coro_stub
-// assume when start, function ptr is in (%esp)
+// assume when start, function ptr is in (%esp) and the resume value is in %eax
 global_asm!(
     ".global {0}",
     "{0}:",
-    "pop eax",
+    "pop ecx",
     "sub esp, 16",
-    "mov [esp], eax",
+    "mov [esp], ecx",
+    "mov [esp + 4], eax",
     "call {call_rust_fn}", // call_rust_fn(...)
-    "push 1",
-    "lea ecx, {MAIN_CTX}",
-    "push ecx",
-    "lea ecx, {CURRENT_CORO_CTX}",
-    "push [ecx]",
-    "call {swap_context}", // swap_context(CURRENT_CORO_CTX, &mut MAIN_CTX, 1)
+    "call {finish_coroutine}", // finish_coroutine()
     sym coro_stub,
-    call_rust_fn = sym call_rust_fn,
-    swap_context = sym swap_context,
-    MAIN_CTX = sym MAIN_CTX,
-    CURRENT_CORO_CTX = sym CURRENT_CORO_CTX,
+    call_rust_fn = sym super::call_rust_fn,
+    finish_coroutine = sym super::finish_coroutine,
 );
 
 // swap_context
@@ -120,6 +91,9 @@ global_asm!(
     "push ebx",
     "push edi",
     "push esi",
+    "sub esp, 8",
+    "stmxcsr [esp]",                   // SSE control/status register
+    "fnstcw [esp + 4]",                // x87 control word
     "mov eax, [ebp + 8]",               // current
     "mov [eax + 4], esp",               // current.resume_esp = %esp
     "lea ecx, co_ret_addr",
@@ -129,6 +103,9 @@ global_asm!(
     "mov eax, [ebp + 16]",              // %eax = val
     "jmp [ecx]",                        // goto next.resume_addr
     "co_ret_addr:",
+    "fldcw [esp + 4]",
+    "ldmxcsr [esp]",
+    "add esp, 8",
     "pop esi",
     "pop edi",
     "pop ebx",
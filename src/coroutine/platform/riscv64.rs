@@ -0,0 +1,150 @@
+use core::arch::global_asm;
+use alloc::boxed::Box;
+
+use super::stack::StackSpace;
+
+pub const BACKEND: &str = "riscv64";
+
+type Address = usize;
+
+#[repr(C)]
+pub struct Context {
+    resume_addr: Address,
+    resume_sp: Address,
+    pub(super) stack_space: Option<StackSpace>,
+    // context which resumed this one and gets control back when it suspends
+    pub(super) resumer: *mut Context,
+    // context which transferred to this one and gets control back when it
+    // finishes, null if it was resumed
+    pub(super) transferrer: *mut Context,
+}
+
+impl Context {
+    pub fn new(mut stack_space: StackSpace, func: impl FnOnce(usize)) -> Context {
+        stack_space.prepare();
+        let size = stack_space.size();
+        let func = Box::new(Box::new(func) as Box<dyn FnOnce(usize)>);
+        let func = Box::into_raw(func);
+        // we use jr to goto coro_stub, sp must stay 16-byte aligned
+        let stack_top = unsafe { stack_space.address.offset(size as isize - 16) };
+        unsafe {
+            (stack_top as *mut usize).write(func as _);
+        }
+        Context {
+            resume_addr: coro_stub as *const () as _,
+            resume_sp: stack_top as _,
+            stack_space: Some(stack_space),
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    // context of a thread, only ever switched away from and back to
+    pub const fn main() -> Context {
+        Context {
+            resume_addr: 0,
+            resume_sp: 0,
+            stack_space: None,
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    // stack pointer saved when the context was last switched away from
+    pub fn stack_pointer(&self) -> usize {
+        self.resume_sp
+    }
+}
+
+#[allow(improper_ctypes)]
+extern "C" {
+    fn coro_stub();
+    pub fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize;
+}
+
+// coro_stub
+// assume when start, function ptr is in 0(sp) and the resume value is in a0
+global_asm!(
+    ".global {0}",
+    "{0}:",
+    "mv a1, a0",
+    "ld a0, 0(sp)",
+    "mv s0, zero",              // end of the frame pointer chain
+    "call {call_rust_fn}",      // call_rust_fn(0(sp), a0)
+    "call {finish_coroutine}",  // finish_coroutine()
+    sym coro_stub,
+    call_rust_fn = sym super::call_rust_fn,
+    finish_coroutine = sym super::finish_coroutine,
+);
+
+// swap_context
+// current: a0
+// next: a1
+// val: a2
+// -> a0
+global_asm!(
+    ".global {0}",
+    "{0}:",
+    "addi sp, sp, -208",
+    "sd ra, 0(sp)",
+    "sd s0, 8(sp)",
+    "sd s1, 16(sp)",
+    "sd s2, 24(sp)",
+    "sd s3, 32(sp)",
+    "sd s4, 40(sp)",
+    "sd s5, 48(sp)",
+    "sd s6, 56(sp)",
+    "sd s7, 64(sp)",
+    "sd s8, 72(sp)",
+    "sd s9, 80(sp)",
+    "sd s10, 88(sp)",
+    "sd s11, 96(sp)",
+    "fsd fs0, 104(sp)",
+    "fsd fs1, 112(sp)",
+    "fsd fs2, 120(sp)",
+    "fsd fs3, 128(sp)",
+    "fsd fs4, 136(sp)",
+    "fsd fs5, 144(sp)",
+    "fsd fs6, 152(sp)",
+    "fsd fs7, 160(sp)",
+    "fsd fs8, 168(sp)",
+    "fsd fs9, 176(sp)",
+    "fsd fs10, 184(sp)",
+    "fsd fs11, 192(sp)",
+    "sd sp, 8(a0)",                     // current.resume_sp = sp
+    "lla t0, co_ret_addr",
+    "sd t0, 0(a0)",                     // current.resume_addr = &&co_ret_addr
+    "ld sp, 8(a1)",                     // sp = next.resume_sp
+    "mv a0, a2",                        // a0 = val
+    "ld t0, 0(a1)",
+    "jr t0",                            // goto next.resume_addr
+    "co_ret_addr:",
+    "fld fs11, 192(sp)",
+    "fld fs10, 184(sp)",
+    "fld fs9, 176(sp)",
+    "fld fs8, 168(sp)",
+    "fld fs7, 160(sp)",
+    "fld fs6, 152(sp)",
+    "fld fs5, 144(sp)",
+    "fld fs4, 136(sp)",
+    "fld fs3, 128(sp)",
+    "fld fs2, 120(sp)",
+    "fld fs1, 112(sp)",
+    "fld fs0, 104(sp)",
+    "ld s11, 96(sp)",
+    "ld s10, 88(sp)",
+    "ld s9, 80(sp)",
+    "ld s8, 72(sp)",
+    "ld s7, 64(sp)",
+    "ld s6, 56(sp)",
+    "ld s5, 48(sp)",
+    "ld s4, 40(sp)",
+    "ld s3, 32(sp)",
+    "ld s2, 24(sp)",
+    "ld s1, 16(sp)",
+    "ld s0, 8(sp)",
+    "ld ra, 0(sp)",
+    "addi sp, sp, 208",
+    "ret",                              // return a0
+    sym swap_context,
+);
@@ -1,108 +1,80 @@
 use core::arch::global_asm;
+use alloc::boxed::Box;
 
-extern crate alloc;
+use super::stack::StackSpace;
 
-use super::Coroutine;
+pub const BACKEND: &str = "sysv64";
 
 type Address = usize;
 
-struct StackSpace {
-    pub address: *mut u8,
-    layout: core::alloc::Layout,
-}
-
-impl StackSpace {
-    // size: szie in byte
-    pub unsafe fn new(size: usize) -> Self {
-        let layout = core::alloc::Layout::from_size_align(size, 16).unwrap();
-        let address = alloc::alloc::alloc(layout);
-        StackSpace { address, layout }
-    }
-}
-
-impl Drop for StackSpace {
-    fn drop(&mut self) {
-        unsafe {
-            alloc::alloc::dealloc(self.address, self.layout);
-        }
-    }
-}
-
 #[repr(C)]
 pub struct Context {
     resume_addr: Address,
     resume_rsp: Address,
-    stack_space: Option<StackSpace>,
+    pub(super) stack_space: Option<StackSpace>,
+    // context which resumed this one and gets control back when it suspends
+    pub(super) resumer: *mut Context,
+    // context which transferred to this one and gets control back when it
+    // finishes, null if it was resumed
+    pub(super) transferrer: *mut Context,
 }
 
 impl Context {
-    pub fn new(func: impl FnOnce()) -> Context {
-        assert!(cfg!(all(target_arch = "x86_64", not(windows))));
-        const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 8;
-        let size = DEFAULT_STACK_SIZE;
-        let func = Box::new(Box::new(func) as Box<dyn FnOnce()>);
+    pub fn new(mut stack_space: StackSpace, func: impl FnOnce(usize)) -> Context {
+        stack_space.prepare();
+        let size = stack_space.size();
+        let func = Box::new(Box::new(func) as Box<dyn FnOnce(usize)>);
         let func = Box::into_raw(func);
-        let stack_space = unsafe { StackSpace::new(size) };
         // we use jmp to goto coro_stub
         let stack_top = unsafe { stack_space.address.offset(size as isize - 8) };
         unsafe {
             (stack_top as *mut usize).write(func as _);
         }
         Context {
-            resume_addr: coro_stub as _,
+            resume_addr: coro_stub as *const () as _,
             resume_rsp: stack_top as _,
             stack_space: Some(stack_space),
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
         }
     }
-}
 
-// context of main thread
-static mut MAIN_CTX: Context = Context {
-    resume_addr: 0,
-    resume_rsp: 0,
-    stack_space: None,
-};
-
-static mut CURRENT_CORO_CTX: *mut Context = core::ptr::null_mut();
-
-pub unsafe fn resume_coroutine(coro: &mut Coroutine, val: usize) -> usize {
-    CURRENT_CORO_CTX = &mut coro.context;
-    swap_context(&mut MAIN_CTX, CURRENT_CORO_CTX, val)
-}
+    // context of a thread, only ever switched away from and back to
+    pub const fn main() -> Context {
+        Context {
+            resume_addr: 0,
+            resume_rsp: 0,
+            stack_space: None,
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
 
-pub unsafe fn return_from_coroutine(ret: usize) -> usize {
-    swap_context(CURRENT_CORO_CTX, &mut MAIN_CTX, ret)
+    // stack pointer saved when the context was last switched away from
+    pub fn stack_pointer(&self) -> usize {
+        self.resume_rsp
+    }
 }
 
 #[allow(improper_ctypes)]
 extern "sysv64" {
     fn coro_stub();
-    fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize;
-}
-
-unsafe extern "C" fn call_rust_fn(func: *mut Box<dyn FnOnce()>) {
-    let func = Box::from_raw(func);
-    func()
+    pub fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize;
 }
 
 // coro_stub
-// assume when start, function ptr is in (%rsp)
+// assume when start, function ptr is in (%rsp) and the resume value is in %rax
 global_asm!(
     ".global {0}",
     "{0}:",
     "mov rdi, [rsp]",
     "add rsp, 8",
-    "call {call_rust_fn}", // call_rust_fn(*%rsp)
-    "lea rdi, [rip + {CURRENT_CORO_CTX}]",
-    "mov rdi, [rdi]",
-    "lea rsi, [rip + {MAIN_CTX}]",
-    "mov rdx, 1",
-    "call {swap_context}", // swap_context(CURRENT_CORO_CTX, &mut MAIN_CTX, 1)
+    "mov rsi, rax",
+    "call {call_rust_fn}", // call_rust_fn(*%rsp, %rax)
+    "call {finish_coroutine}", // finish_coroutine()
     sym coro_stub,
-    call_rust_fn = sym call_rust_fn,
-    swap_context = sym swap_context,
-    MAIN_CTX = sym MAIN_CTX,
-    CURRENT_CORO_CTX = sym CURRENT_CORO_CTX,
+    call_rust_fn = sym super::call_rust_fn,
+    finish_coroutine = sym super::finish_coroutine,
 );
 
 // swap_context
@@ -119,6 +91,9 @@ global_asm!(
     "push r15",
     "push rbx",
     "push rbp",
+    "sub rsp, 8",
+    "stmxcsr [rsp]",                   // SSE control/status register
+    "fnstcw [rsp + 4]",                // x87 control word
     "mov [rdi + 8], rsp",               // current.resume_rsp = %rsp
     "lea rax, [rip + co_ret_addr]",
     "mov [rdi], rax",                   // current.resume_addr = &&co_ret_addr
@@ -126,6 +101,9 @@ global_asm!(
     "mov rax, rdx",                     // %rax = val
     "jmp [rsi]",                          // goto next.resume_addr
     "co_ret_addr:",
+    "fldcw [rsp + 4]",
+    "ldmxcsr [rsp]",
+    "add rsp, 8",
     "pop rbp",
     "pop rbx",
     "pop r15",
@@ -0,0 +1,103 @@
+// Portable backend on top of getcontext/makecontext/swapcontext, for targets
+// without a hand-written one. swapcontext also saves the signal mask with a
+// system call, so switching is a lot slower than with the others.
+
+use alloc::boxed::Box;
+use core::cell::Cell;
+
+use super::stack::StackSpace;
+
+pub const BACKEND: &str = "ucontext";
+
+pub struct Context {
+    // boxed, a ucontext_t may point into itself; that of a thread is only
+    // allocated once it is first switched away from
+    ucontext: Option<Box<libc::ucontext_t>>,
+    // approximate stack pointer, taken by `swap_context` since where the real
+    // one is kept in `ucontext` differs by platform
+    resume_sp: usize,
+    // function to run when first resumed
+    func: *mut Box<dyn FnOnce(usize)>,
+    pub(super) stack_space: Option<StackSpace>,
+    // context which resumed this one and gets control back when it suspends
+    pub(super) resumer: *mut Context,
+    // context which transferred to this one and gets control back when it
+    // finishes, null if it was resumed
+    pub(super) transferrer: *mut Context,
+}
+
+impl Context {
+    pub fn new(mut stack_space: StackSpace, func: impl FnOnce(usize)) -> Context {
+        stack_space.prepare();
+        let func = Box::new(Box::new(func) as Box<dyn FnOnce(usize)>);
+        let func = Box::into_raw(func);
+        let mut ucontext: Box<libc::ucontext_t> = Box::new(unsafe { core::mem::zeroed() });
+        unsafe {
+            if libc::getcontext(&mut *ucontext) != 0 {
+                panic!("getcontext failed: {}", std::io::Error::last_os_error());
+            }
+            ucontext.uc_stack.ss_sp = stack_space.address as _;
+            ucontext.uc_stack.ss_size = stack_space.size();
+            ucontext.uc_link = core::ptr::null_mut();
+            libc::makecontext(&mut *ucontext, coro_entry, 0);
+        }
+        Context {
+            ucontext: Some(ucontext),
+            resume_sp: stack_space.top(),
+            func: func as _,
+            stack_space: Some(stack_space),
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    // context of a thread, only ever switched away from and back to
+    pub const fn main() -> Context {
+        Context {
+            ucontext: None,
+            resume_sp: 0,
+            func: core::ptr::null_mut(),
+            stack_space: None,
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    // stack pointer saved when the context was last switched away from
+    pub fn stack_pointer(&self) -> usize {
+        self.resume_sp
+    }
+}
+
+std::thread_local! {
+    // `val` of the last `swap_context`, which swapcontext cannot pass along
+    static SWAP_VAL: Cell<usize> = const { Cell::new(0) };
+
+    // context switched to by the last `swap_context`, for `coro_entry` to
+    // find its function in
+    static SWAP_NEXT: Cell<*mut Context> = const { Cell::new(core::ptr::null_mut()) };
+}
+
+pub unsafe fn swap_context(current: *mut Context, next: *mut Context, val: usize) -> usize {
+    let marker = 0u8;
+    (*current).resume_sp = std::hint::black_box(&marker) as *const u8 as usize;
+    SWAP_VAL.set(val);
+    SWAP_NEXT.set(next);
+    let ucontext = (*current)
+        .ucontext
+        .get_or_insert_with(|| Box::new(core::mem::zeroed()));
+    if libc::swapcontext(&mut **ucontext, &**(*next).ucontext.as_ref().unwrap()) != 0 {
+        panic!("swapcontext failed: {}", std::io::Error::last_os_error());
+    }
+    SWAP_VAL.get()
+}
+
+// what makecontext starts a coroutine with, in place of coro_stub
+extern "C" fn coro_entry() {
+    unsafe {
+        let current = SWAP_NEXT.get();
+        let func = core::mem::replace(&mut (*current).func, core::ptr::null_mut());
+        super::call_rust_fn(func, SWAP_VAL.get());
+        super::finish_coroutine()
+    }
+}
@@ -0,0 +1,35 @@
+// Stand-in for when the target and features leave no backend, so that the
+// `compile_error!` in lib.rs saying so is the only error reported. Nothing
+// here is ever run.
+
+use super::stack::StackSpace;
+
+pub const BACKEND: &str = "unsupported";
+
+pub struct Context {
+    pub(super) stack_space: Option<StackSpace>,
+    pub(super) resumer: *mut Context,
+    pub(super) transferrer: *mut Context,
+}
+
+impl Context {
+    pub fn new(_stack_space: StackSpace, _func: impl FnOnce(usize)) -> Context {
+        unreachable!("no context switch backend")
+    }
+
+    pub const fn main() -> Context {
+        Context {
+            stack_space: None,
+            resumer: core::ptr::null_mut(),
+            transferrer: core::ptr::null_mut(),
+        }
+    }
+
+    pub fn stack_pointer(&self) -> usize {
+        unreachable!("no context switch backend")
+    }
+}
+
+pub unsafe fn swap_context(_current: *mut Context, _next: *mut Context, _val: usize) -> usize {
+    unreachable!("no context switch backend")
+}
@@ -0,0 +1,576 @@
+//! A run queue of coroutines on one thread, resumed in turn until they have
+//! all finished.
+//!
+//! Tasks are coroutines created without a `Yielder`. One goes back to the end
+//! of the queue whenever it calls `yield_now`, and leaves the queue when it
+//! calls `park`, until its `Task` is unparked, or when it calls `sleep`,
+//! until its timer expires.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::rc::{Rc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::marker::PhantomData;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::time::Instant;
+
+use super::{
+    current_stack, yield_now, Coroutine, CoroutineBuilder, CoroutineResult, PanicPayload,
+    ResumeError,
+};
+
+// Coroutine of a task, returning what the function of the task returned.
+type TaskCoroutine<'a> = Coroutine<'a, (), (), Box<dyn Any>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // in the ready queue
+    Ready,
+    Running,
+    // out of the ready queue until unparked
+    Parked,
+    Finished,
+}
+
+// What the handles of a task share with its scheduler.
+struct Header {
+    state: Cell<State>,
+    // `unpark` was called while the task was not parked
+    notified: Cell<bool>,
+    // bottom of the stack of the task, telling its coroutine apart from the
+    // ones it resumes
+    stack: usize,
+    queue: Weak<Queue>,
+    // what the task returned or panicked with, until joined
+    result: RefCell<Option<Result<Box<dyn Any>, PanicPayload>>>,
+    // tasks to unpark once it finishes
+    joiners: RefCell<Vec<Task>>,
+    // earliest deadline of the `timeout`s the task is in, with the token of
+    // the one it belongs to
+    deadline: Cell<Option<(Instant, u64)>>,
+}
+
+impl Header {
+    // Unwinds out of the `timeout` whose deadline has passed, if any.
+    fn check_deadline(&self) {
+        if let Some((deadline, token)) = self.deadline.get() {
+            if !std::thread::panicking() && Instant::now() >= deadline {
+                resume_unwind(Box::new(TimedOut(token)));
+            }
+        }
+    }
+}
+
+// The part of a scheduler reachable from within its tasks.
+#[derive(Default)]
+struct Queue {
+    ready: RefCell<VecDeque<Rc<Header>>>,
+    // tasks spawned by `spawn`, for `run` to take over
+    spawned: RefCell<Vec<(Rc<Header>, TaskCoroutine<'static>)>>,
+    // tasks to unpark at a deadline, keyed by it and a token ordering those
+    // with the same deadline
+    timers: RefCell<BTreeMap<(Instant, u64), Task>>,
+    // numbers the timers and the `timeout`s
+    next_token: Cell<u64>,
+}
+
+impl Queue {
+    fn new_task<'a, T: 'static>(
+        self: &Rc<Self>,
+        builder: CoroutineBuilder,
+        func: impl FnOnce() -> T + 'a,
+    ) -> (Rc<Header>, TaskCoroutine<'a>) {
+        let coro = builder.build(move || Box::new(func()) as Box<dyn Any>);
+        let stack = coro.context.stack_space().map_or(0, |stack| stack.address as usize);
+        let header = Rc::new(Header {
+            state: Cell::new(State::Ready),
+            notified: Cell::new(false),
+            stack,
+            queue: Rc::downgrade(self),
+            result: RefCell::new(None),
+            joiners: RefCell::new(Vec::new()),
+            deadline: Cell::new(None),
+        });
+        self.ready.borrow_mut().push_back(header.clone());
+        (header, coro)
+    }
+
+    fn token(&self) -> u64 {
+        let token = self.next_token.get();
+        self.next_token.set(token + 1);
+        token
+    }
+
+    // Unparks `task` at `deadline`, unless the returned guard is dropped
+    // first.
+    fn add_timer(self: &Rc<Self>, deadline: Instant, task: Task) -> TimerGuard {
+        let key = (deadline, self.token());
+        self.timers.borrow_mut().insert(key, task);
+        TimerGuard {
+            queue: Rc::downgrade(self),
+            key,
+        }
+    }
+
+    // Unparks the tasks whose timers have expired, and returns when the next
+    // one expires.
+    fn fire_timers(&self) -> Option<Instant> {
+        let mut timers = self.timers.borrow_mut();
+        let mut now = None;
+        while let Some(timer) = timers.first_entry() {
+            let deadline = timer.key().0;
+            if deadline > *now.get_or_insert_with(Instant::now) {
+                return Some(deadline);
+            }
+            timer.remove().unpark();
+        }
+        None
+    }
+}
+
+// Takes its timer out of the queue once the task no longer waits for it.
+struct TimerGuard {
+    queue: Weak<Queue>,
+    key: (Instant, u64),
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.upgrade() {
+            queue.timers.borrow_mut().remove(&self.key);
+        }
+    }
+}
+
+// Unwinds a task out of the `timeout` with this token.
+struct TimedOut(u64);
+
+std::thread_local! {
+    // queue of the scheduler running on this thread
+    static CURRENT_QUEUE: RefCell<Option<Rc<Queue>>> = const { RefCell::new(None) };
+
+    // task being resumed by that scheduler
+    static CURRENT_TASK: RefCell<Option<Rc<Header>>> = const { RefCell::new(None) };
+}
+
+// Task whose own coroutine is running, rather than one resumed by it.
+fn current_header() -> Option<Rc<Header>> {
+    let header = CURRENT_TASK.with_borrow(Option::clone)?;
+    let stack = unsafe { current_stack() }?;
+    (stack.address as usize == header.stack).then_some(header)
+}
+
+// Key of a task in `Scheduler::tasks`.
+fn key(header: &Rc<Header>) -> usize {
+    Rc::as_ptr(header) as usize
+}
+
+/// Runs coroutines on the current thread, each until it yields, parks or
+/// returns, taking the ready ones in turn.
+///
+/// Borrows of lifetime `'a` may be alive in its tasks.
+pub struct Scheduler<'a> {
+    queue: Rc<Queue>,
+    // the coroutines of the tasks, but for the one being resumed
+    tasks: RefCell<HashMap<usize, TaskCoroutine<'a>>>,
+}
+
+impl<'a> Scheduler<'a> {
+    /// Creates a scheduler without tasks.
+    pub fn new() -> Self {
+        Scheduler {
+            queue: Rc::default(),
+            tasks: RefCell::default(),
+        }
+    }
+
+    /// Adds a task running `func` on a coroutine with the default
+    /// configuration of `CoroutineBuilder`. It first runs once `run` gets to
+    /// it.
+    pub fn spawn<T: 'static>(&self, func: impl FnOnce() -> T + 'a) -> JoinHandle<T> {
+        self.spawn_with(CoroutineBuilder::new(), func)
+    }
+
+    /// Like `spawn`, for a coroutine configured by `builder`.
+    pub fn spawn_with<T: 'static>(
+        &self,
+        builder: CoroutineBuilder,
+        func: impl FnOnce() -> T + 'a,
+    ) -> JoinHandle<T> {
+        let (header, coro) = self.queue.new_task(builder, func);
+        self.tasks.borrow_mut().insert(key(&header), coro);
+        JoinHandle::new(header)
+    }
+
+    /// Resumes the ready tasks in turn, including ones spawned meanwhile and
+    /// ones whose timers expire, blocking the thread while all are asleep.
+    /// Returns once every task has finished, or the remaining ones are all
+    /// parked without a timer, in which case they wait for being unparked
+    /// and `run` being called again.
+    ///
+    /// A task which panics finishes with the panic, which is handed to
+    /// `JoinHandle::join`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another scheduler is running on this thread.
+    pub fn run(&self) {
+        CURRENT_QUEUE.with_borrow_mut(|current| {
+            assert!(current.is_none(), "`Scheduler::run` called while a scheduler is running");
+            *current = Some(self.queue.clone());
+        });
+        let _running = Running;
+
+        loop {
+            self.take_spawned();
+            let next_timer = self.queue.fire_timers();
+            let next = self.queue.ready.borrow_mut().pop_front();
+            let Some(header) = next else {
+                match next_timer {
+                    Some(deadline) => {
+                        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+            let mut coro = self.tasks.borrow_mut().remove(&key(&header)).unwrap();
+            header.state.set(State::Running);
+            let prev = CURRENT_TASK.replace(Some(header.clone()));
+            let result = coro.try_resume(());
+            CURRENT_TASK.set(prev);
+
+            let result = match result {
+                Ok(CoroutineResult::Yielded(())) => {
+                    if header.state.get() == State::Running {
+                        header.state.set(State::Ready);
+                        self.queue.ready.borrow_mut().push_back(header.clone());
+                    }
+                    self.tasks.borrow_mut().insert(key(&header), coro);
+                    continue;
+                }
+                Ok(CoroutineResult::Complete(ret)) => Ok(ret),
+                Err(ResumeError::Panicked(payload)) => Err(payload),
+                Err(err) => unreachable!("task could not be resumed: {}", err),
+            };
+            drop(coro);
+            *header.result.borrow_mut() = Some(result);
+            header.state.set(State::Finished);
+            for joiner in header.joiners.take() {
+                joiner.unpark();
+            }
+        }
+    }
+
+    fn take_spawned(&self) {
+        let spawned = core::mem::take(&mut *self.queue.spawned.borrow_mut());
+        let mut tasks = self.tasks.borrow_mut();
+        for (header, coro) in spawned {
+            tasks.insert(key(&header), coro);
+        }
+    }
+}
+
+impl Default for Scheduler<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Scheduler<'_> {
+    /// Drops the tasks which have not finished, unwinding their stacks.
+    fn drop(&mut self) {
+        // what is dropped on their stacks may spawn more
+        loop {
+            self.take_spawned();
+            let tasks = core::mem::take(&mut *self.tasks.borrow_mut());
+            if tasks.is_empty() {
+                break;
+            }
+            drop(tasks);
+        }
+        self.queue.ready.borrow_mut().clear();
+        self.queue.timers.borrow_mut().clear();
+    }
+}
+
+// Clears what `run` set up for its tasks, also when it unwinds.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        CURRENT_QUEUE.set(None);
+    }
+}
+
+/// Handle to a task of a scheduler, to wake it up once it parks.
+#[derive(Clone)]
+pub struct Task {
+    header: Rc<Header>,
+}
+
+impl Task {
+    /// Puts the task back into the ready queue if it is parked. Otherwise its
+    /// next `park` returns right away.
+    pub fn unpark(&self) {
+        let header = &self.header;
+        match header.state.get() {
+            State::Parked => {
+                header.state.set(State::Ready);
+                if let Some(queue) = header.queue.upgrade() {
+                    queue.ready.borrow_mut().push_back(header.clone());
+                }
+            }
+            State::Ready | State::Running => header.notified.set(true),
+            State::Finished => {}
+        }
+    }
+
+    /// Whether the task has returned.
+    pub fn is_finished(&self) -> bool {
+        self.header.state.get() == State::Finished
+    }
+}
+
+/// Handle to a spawned task, to wait for it to finish and take what it
+/// returned.
+pub struct JoinHandle<T> {
+    task: Task,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: 'static> JoinHandle<T> {
+    fn new(header: Rc<Header>) -> Self {
+        JoinHandle {
+            task: Task { header },
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The task, to unpark it.
+    pub fn task(&self) -> &Task {
+        &self.task
+    }
+
+    /// Whether the task has returned.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Parks the running task until the task of the handle has finished, and
+    /// returns what it returned, or what it panicked with as `Err`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the task has not finished and this is not called from
+    /// another task, or from a coroutine resumed by one.
+    pub fn join(self) -> Result<T, PanicPayload> {
+        let header = &self.task.header;
+        if !self.is_finished() {
+            let current = current_header().expect("`join` of an unfinished task called outside of a task");
+            assert!(!Rc::ptr_eq(&current, header), "a task cannot join itself");
+            header.joiners.borrow_mut().push(Task { header: current });
+            while !self.is_finished() {
+                park();
+            }
+        }
+        let result = header.result.take().unwrap();
+        result.map(|ret| *ret.downcast::<T>().unwrap())
+    }
+}
+
+/// Adds a task to the scheduler running on this thread, see
+/// `Scheduler::spawn`.
+///
+/// # Panics
+///
+/// Panics if no scheduler is running on this thread.
+pub fn spawn<T: 'static>(func: impl FnOnce() -> T + 'static) -> JoinHandle<T> {
+    let queue = CURRENT_QUEUE
+        .with_borrow(Option::clone)
+        .expect("`spawn` called while no scheduler is running");
+    let (header, coro) = queue.new_task(CoroutineBuilder::new(), func);
+    queue.spawned.borrow_mut().push((header.clone(), coro));
+    JoinHandle::new(header)
+}
+
+/// The task running on this thread.
+///
+/// # Panics
+///
+/// Panics if not called from a task, or from a coroutine resumed by one.
+pub fn current() -> Task {
+    let header = current_header().expect("`current` called outside of a task");
+    Task { header }
+}
+
+/// Takes the running task out of the ready queue until its `Task` is
+/// unparked, running the other tasks meanwhile. Returns right away if it was
+/// unparked since it last parked.
+///
+/// Within `timeout`, unwinds back to it instead once its deadline passes.
+///
+/// # Panics
+///
+/// Panics if not called from a task, or from a coroutine resumed by one.
+pub fn park() {
+    let header = current_header().expect("`park` called outside of a task");
+    header.check_deadline();
+    if !header.notified.replace(false) {
+        header.state.set(State::Parked);
+        yield_now();
+    }
+    header.check_deadline();
+}
+
+/// Parks the running task for at least `duration`.
+///
+/// # Panics
+///
+/// Panics if not called from a task, or from a coroutine resumed by one.
+pub fn sleep(duration: Duration) {
+    match Instant::now().checked_add(duration) {
+        Some(deadline) => sleep_until(deadline),
+        // as good as forever
+        None => loop {
+            park();
+        },
+    }
+}
+
+/// Parks the running task until `deadline`.
+///
+/// # Panics
+///
+/// Panics if not called from a task, or from a coroutine resumed by one.
+pub fn sleep_until(deadline: Instant) {
+    let header = current_header().expect("`sleep` called outside of a task");
+    let queue = header.queue.upgrade().unwrap();
+    let _timer = queue.add_timer(deadline, Task { header });
+    while Instant::now() < deadline {
+        park();
+    }
+}
+
+/// Runs `func` on the running task, giving up on it once `duration` has
+/// passed. It is then unwound from where it parks, dropping what is alive
+/// on its part of the stack, and `Err` is returned.
+///
+/// `func` is only stopped while it waits for something, by blocking on a
+/// channel, a lock, `JoinHandle::join` or `sleep`, or by calling `park`.
+///
+/// # Panics
+///
+/// Panics if not called from a task, or from a coroutine resumed by one,
+/// and re-raises the panic if `func` panics.
+pub fn timeout<T>(duration: Duration, func: impl FnOnce() -> T) -> Result<T, Elapsed> {
+    let header = current_header().expect("`timeout` called outside of a task");
+    let Some(deadline) = Instant::now().checked_add(duration) else {
+        return Ok(func());
+    };
+    let queue = header.queue.upgrade().unwrap();
+    let token = queue.token();
+    let outer = header.deadline.get();
+    if outer.is_none_or(|(outer, _)| deadline < outer) {
+        header.deadline.set(Some((deadline, token)));
+    }
+    let timer = queue.add_timer(deadline, Task { header: header.clone() });
+
+    let result = catch_unwind(AssertUnwindSafe(func));
+    drop(timer);
+    header.deadline.set(outer);
+    match result {
+        Ok(ret) => Ok(ret),
+        Err(payload) => match payload.downcast::<TimedOut>() {
+            Ok(timed_out) if timed_out.0 == token => Err(Elapsed(())),
+            Ok(timed_out) => resume_unwind(timed_out),
+            Err(payload) => resume_unwind(payload),
+        },
+    }
+}
+
+/// Error of `timeout` when the function did not return in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl core::error::Error for Elapsed {}
+
+// Tasks parked until something they wait for happens, woken in the order
+// they started waiting. The building block of the channels and locks.
+#[derive(Default)]
+pub(crate) struct WaitQueue {
+    waiters: RefCell<VecDeque<Rc<Waiter>>>,
+}
+
+struct Waiter {
+    task: Task,
+    woken: Cell<bool>,
+}
+
+impl WaitQueue {
+    // Parks the running task until `notify_one` or `notify_all` picks it.
+    // Panics if not called from a task.
+    pub(crate) fn wait(&self) {
+        let header = current_header().expect("cannot block outside of a task");
+        let waiter = Rc::new(Waiter {
+            task: Task { header },
+            woken: Cell::new(false),
+        });
+        self.waiters.borrow_mut().push_back(waiter.clone());
+        // leave the queue if the task is unwound while waiting
+        let cancel = CancelWait { queue: self, waiter: &waiter };
+        while !waiter.woken.get() {
+            park();
+        }
+        core::mem::forget(cancel);
+    }
+
+    // Wakes the task which has waited longest, returns whether there was one.
+    pub(crate) fn notify_one(&self) -> bool {
+        let waiter = self.waiters.borrow_mut().pop_front();
+        match waiter {
+            Some(waiter) => {
+                waiter.woken.set(true);
+                waiter.task.unpark();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn notify_all(&self) {
+        while self.notify_one() {}
+    }
+
+    pub(crate) fn has_waiters(&self) -> bool {
+        !self.waiters.borrow().is_empty()
+    }
+}
+
+struct CancelWait<'a> {
+    queue: &'a WaitQueue,
+    waiter: &'a Rc<Waiter>,
+}
+
+impl Drop for CancelWait<'_> {
+    fn drop(&mut self) {
+        if self.waiter.woken.get() {
+            // woken but unwound before it got to run, such as by a `timeout`,
+            // so the wakeup goes to the next waiter instead of being lost
+            self.queue.notify_one();
+        } else {
+            self.queue.waiters.borrow_mut().retain(|waiter| !Rc::ptr_eq(waiter, self.waiter));
+        }
+    }
+}
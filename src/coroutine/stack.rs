@@ -0,0 +1,435 @@
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cell::{Cell, OnceCell, RefCell};
+
+#[cfg(all(unix, feature = "guard-pages"))]
+use super::guard;
+
+// alignment of every coroutine stack
+const STACK_ALIGN: usize = 16;
+
+// what stacks measuring their usage are filled with
+const STACK_CANARY: usize = usize::from_ne_bytes([0xa5; core::mem::size_of::<usize>()]);
+
+/// Where the stacks of coroutines come from.
+///
+/// # Safety
+///
+/// `allocate` must return null or the lowest address of `layout.size()`
+/// writable bytes aligned to `layout.align()`, which stay valid until they
+/// are passed to `deallocate`.
+pub unsafe trait StackAllocator {
+    /// Allocates a stack of `layout`, returning null on failure.
+    fn allocate(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    ///
+    /// `address` must have been returned by `allocate` on this allocator with
+    /// the same `layout`.
+    unsafe fn deallocate(&self, address: *mut u8, layout: Layout);
+
+    /// Size of the inaccessible guard region right below every stack, where
+    /// running into it is reported as a stack overflow. 0 if there is none.
+    fn guard_size(&self) -> usize {
+        0
+    }
+}
+
+unsafe impl<A: StackAllocator + ?Sized> StackAllocator for Rc<A> {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, address: *mut u8, layout: Layout) {
+        (**self).deallocate(address, layout)
+    }
+
+    fn guard_size(&self) -> usize {
+        (**self).guard_size()
+    }
+}
+
+/// What `StackPool` allocates stacks with unless told otherwise.
+#[cfg(all(unix, feature = "guard-pages"))]
+pub type DefaultStackAllocator = MmapStackAllocator;
+/// What `StackPool` allocates stacks with unless told otherwise.
+#[cfg(not(all(unix, feature = "guard-pages")))]
+pub type DefaultStackAllocator = HeapStackAllocator;
+
+/// Allocates stacks with the global allocator, without a guard region.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapStackAllocator;
+
+unsafe impl StackAllocator for HeapStackAllocator {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc::alloc::alloc(layout) }
+    }
+
+    unsafe fn deallocate(&self, address: *mut u8, layout: Layout) {
+        alloc::alloc::dealloc(address, layout);
+    }
+}
+
+/// Allocates stacks with `mmap`, each with a `PROT_NONE` guard page below
+/// it, so that a stack overflow faults instead of overwriting other memory.
+#[cfg(all(unix, feature = "guard-pages"))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MmapStackAllocator;
+
+#[cfg(all(unix, feature = "guard-pages"))]
+impl MmapStackAllocator {
+    // size of the whole mapping for a stack of `size` bytes
+    fn mapping_size(size: usize) -> usize {
+        let page = guard::page_size();
+        size.div_ceil(page) * page + page
+    }
+}
+
+#[cfg(all(unix, feature = "guard-pages"))]
+unsafe impl StackAllocator for MmapStackAllocator {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        guard::install_overflow_handler();
+        let page = guard::page_size();
+        let len = Self::mapping_size(layout.size());
+        unsafe {
+            let base = libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return core::ptr::null_mut();
+            }
+            if libc::mprotect(base, page, libc::PROT_NONE) != 0 {
+                libc::munmap(base, len);
+                return core::ptr::null_mut();
+            }
+            (base as *mut u8).add(page)
+        }
+    }
+
+    unsafe fn deallocate(&self, address: *mut u8, layout: Layout) {
+        let base = address.sub(guard::page_size());
+        libc::munmap(base as _, Self::mapping_size(layout.size()));
+    }
+
+    fn guard_size(&self) -> usize {
+        guard::page_size()
+    }
+}
+
+/// Keeps freed stacks around to hand them out again instead of going to the
+/// underlying allocator for every coroutine.
+///
+/// Requests are rounded up to the smallest size class that fits them, those
+/// larger than every size class bypass the pool. At most `max_retained` free
+/// stacks are kept, in all size classes together.
+pub struct StackPool {
+    allocator: Box<dyn StackAllocator>,
+    size_classes: Vec<usize>,
+    max_retained: Cell<usize>,
+    // free stacks of each size class
+    free: RefCell<Vec<Vec<*mut u8>>>,
+    retained: Cell<usize>,
+}
+
+impl StackPool {
+    /// Size classes of a pool created by `new`.
+    pub const DEFAULT_SIZE_CLASSES: [usize; 5] = [
+        16 * 1024,
+        64 * 1024,
+        256 * 1024,
+        1024 * 1024,
+        8 * 1024 * 1024,
+    ];
+    /// How many free stacks a new pool keeps.
+    pub const DEFAULT_MAX_RETAINED: usize = 64;
+
+    /// Creates a pool over `DefaultStackAllocator` with the default size
+    /// classes.
+    pub fn new() -> Self {
+        Self::with_size_classes(DefaultStackAllocator::default(), &Self::DEFAULT_SIZE_CLASSES)
+    }
+
+    /// Creates a pool allocating stacks of the given sizes from `allocator`.
+    pub fn with_size_classes(
+        allocator: impl StackAllocator + 'static,
+        size_classes: &[usize],
+    ) -> Self {
+        let mut size_classes = size_classes.to_vec();
+        size_classes.sort_unstable();
+        size_classes.dedup();
+        StackPool {
+            allocator: Box::new(allocator),
+            free: RefCell::new(vec![Vec::new(); size_classes.len()]),
+            size_classes,
+            max_retained: Cell::new(Self::DEFAULT_MAX_RETAINED),
+            retained: Cell::new(0),
+        }
+    }
+
+    /// The pool of the current thread, which `CoroutineBuilder` allocates
+    /// stacks from by default.
+    pub fn current() -> Rc<StackPool> {
+        local! {
+            static CURRENT: OnceCell<Rc<StackPool>> = const { OnceCell::new() };
+        }
+        CURRENT.with(|pool| pool.get_or_init(|| Rc::new(StackPool::new())).clone())
+    }
+
+    /// How many free stacks the pool keeps at most.
+    pub fn max_retained(&self) -> usize {
+        self.max_retained.get()
+    }
+
+    /// Sets how many free stacks the pool keeps, freeing those beyond it.
+    pub fn set_max_retained(&self, max: usize) {
+        self.max_retained.set(max);
+        self.trim(max);
+    }
+
+    /// Number of free stacks kept by the pool.
+    pub fn retained(&self) -> usize {
+        self.retained.get()
+    }
+
+    /// Frees all stacks kept by the pool.
+    pub fn clear(&self) {
+        self.trim(0);
+    }
+
+    fn trim(&self, max: usize) {
+        let mut free = self.free.borrow_mut();
+        for (class, stacks) in free.iter_mut().enumerate().rev() {
+            let layout = self.class_layout(class);
+            while self.retained.get() > max {
+                let Some(address) = stacks.pop() else {
+                    break;
+                };
+                self.retained.set(self.retained.get() - 1);
+                unsafe { self.allocator.deallocate(address, layout) };
+            }
+        }
+    }
+
+    fn class_layout(&self, class: usize) -> Layout {
+        Layout::from_size_align(self.size_classes[class], STACK_ALIGN).unwrap()
+    }
+
+    // size class `layout` is served from, and what is allocated for it
+    fn class_of(&self, layout: Layout) -> Option<(usize, Layout)> {
+        if layout.align() > STACK_ALIGN {
+            return None;
+        }
+        let class = self.size_classes.iter().position(|&size| size >= layout.size())?;
+        Some((class, self.class_layout(class)))
+    }
+}
+
+impl Default for StackPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl StackAllocator for StackPool {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        let Some((class, class_layout)) = self.class_of(layout) else {
+            return self.allocator.allocate(layout);
+        };
+        match self.free.borrow_mut()[class].pop() {
+            Some(address) => {
+                self.retained.set(self.retained.get() - 1);
+                address
+            }
+            None => self.allocator.allocate(class_layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, address: *mut u8, layout: Layout) {
+        let Some((class, class_layout)) = self.class_of(layout) else {
+            return self.allocator.deallocate(address, layout);
+        };
+        if self.retained.get() < self.max_retained.get() {
+            self.free.borrow_mut()[class].push(address);
+            self.retained.set(self.retained.get() + 1);
+        } else {
+            self.allocator.deallocate(address, class_layout);
+        }
+    }
+
+    fn guard_size(&self) -> usize {
+        self.allocator.guard_size()
+    }
+}
+
+impl Drop for StackPool {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Memory a coroutine runs on, which it holds on to for as long as it
+/// lives, such as a buffer from an arena or a static one.
+///
+/// # Safety
+///
+/// `base` must always return the lowest address of `size()` writable bytes,
+/// which nothing else touches while the stack is alive. The value is not
+/// moved once `base` has been called, so the memory can be part of it, but
+/// memory outside of it must not move when the value does.
+pub unsafe trait Stack {
+    /// Lowest address of the stack.
+    fn base(&mut self) -> *mut u8;
+
+    /// Size of the stack in bytes.
+    fn size(&self) -> usize;
+}
+
+unsafe impl Stack for &mut [u8] {
+    fn base(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+unsafe impl Stack for Box<[u8]> {
+    fn base(&mut self) -> *mut u8 {
+        self.as_mut_ptr()
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+// where the memory of a `StackSpace` comes from
+enum Memory {
+    Allocated(Rc<dyn StackAllocator>, Layout),
+    // lifetime erased, the coroutine keeps the borrow
+    Provided(*mut Box<dyn Stack>),
+}
+
+pub struct StackSpace {
+    pub address: *mut u8,
+    size: usize,
+    // only looked at by the overflow handler
+    #[cfg_attr(not(all(unix, feature = "guard-pages")), allow(dead_code))]
+    guard_size: usize,
+    // to be filled with `STACK_CANARY` by `prepare`
+    canary: bool,
+    memory: Memory,
+}
+
+impl StackSpace {
+    // size: size in byte, rounded up to the stack alignment
+    // canary: whether to fill the stack to measure its usage
+    pub fn new(size: usize, allocator: Rc<dyn StackAllocator>, canary: bool) -> Self {
+        let layout = Layout::from_size_align(size, STACK_ALIGN)
+            .unwrap()
+            .pad_to_align();
+        let address = allocator.allocate(layout);
+        if address.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+        StackSpace {
+            address,
+            size: layout.size(),
+            guard_size: allocator.guard_size(),
+            canary,
+            memory: Memory::Allocated(allocator, layout),
+        }
+    }
+
+    // a stack in memory handed to us, its size rounded down to the stack
+    // alignment
+    pub fn provided<'a>(stack: impl Stack + 'a, canary: bool) -> Self {
+        // boxed first, the memory may be part of the value
+        let stack = Box::into_raw(Box::new(Box::new(stack) as Box<dyn Stack + 'a>));
+        let (address, size) = unsafe { ((*stack).base(), (*stack).size()) };
+        let size = size / STACK_ALIGN * STACK_ALIGN;
+        StackSpace {
+            address,
+            size,
+            guard_size: 0,
+            canary,
+            memory: Memory::Provided(stack as _),
+        }
+    }
+
+    // Checks that the stack can be run on, and fills it with the canary if
+    // asked to. Called before anything is put on it.
+    pub fn prepare(&mut self) {
+        assert!(
+            (self.address as usize).is_multiple_of(STACK_ALIGN),
+            "coroutine stack at {:p} is not aligned to {} bytes",
+            self.address,
+            STACK_ALIGN
+        );
+        let min = super::CoroutineBuilder::MIN_STACK_SIZE;
+        assert!(
+            self.size >= min,
+            "coroutine stack of {} bytes is smaller than the minimum of {}",
+            self.size,
+            min
+        );
+        if self.canary {
+            let words = self.size / core::mem::size_of::<usize>();
+            unsafe {
+                core::slice::from_raw_parts_mut(self.address as *mut usize, words).fill(STACK_CANARY);
+            }
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn contains(&self, addr: usize) -> bool {
+        let bottom = self.address as usize;
+        (bottom..bottom + self.size()).contains(&addr)
+    }
+
+    pub fn top(&self) -> usize {
+        self.address as usize + self.size()
+    }
+
+    // Bytes between the top of the stack and the lowest one ever written,
+    // found by looking for where the canary is intact. `None` if the stack
+    // was not filled with it.
+    pub fn peak_usage(&self) -> Option<usize> {
+        if !self.canary {
+            return None;
+        }
+        let words = self.size() / core::mem::size_of::<usize>();
+        let stack = unsafe { core::slice::from_raw_parts(self.address as *const usize, words) };
+        let untouched = stack.iter().take_while(|&&word| word == STACK_CANARY).count();
+        Some(self.size() - untouched * core::mem::size_of::<usize>())
+    }
+
+    // whether `addr` lies in the guard region below the stack
+    #[cfg_attr(not(all(unix, feature = "guard-pages")), allow(dead_code))]
+    pub fn guard_contains(&self, addr: usize) -> bool {
+        let bottom = self.address as usize;
+        (bottom - self.guard_size..bottom).contains(&addr)
+    }
+}
+
+impl Drop for StackSpace {
+    fn drop(&mut self) {
+        unsafe {
+            match &self.memory {
+                Memory::Allocated(allocator, layout) => allocator.deallocate(self.address, *layout),
+                Memory::Provided(stack) => drop(Box::from_raw(*stack)),
+            }
+        }
+    }
+}
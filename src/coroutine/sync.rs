@@ -0,0 +1,523 @@
+//! Locks and other synchronization between the tasks of a scheduler, in the
+//! manner of `std::sync`.
+//!
+//! Where the ones of `std::sync` would block the thread, and with it every
+//! task on it, these park the running task until it can go on, so the other
+//! tasks keep running meanwhile. They can therefore only block in a task, but
+//! can be used without blocking from anywhere on the thread. There is no
+//! poisoning: a task panicking while holding a lock releases it.
+
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+
+use super::scheduler::WaitQueue;
+
+/// A lock giving one task at a time access to `T`.
+pub struct Mutex<T: ?Sized> {
+    locked: Cell<bool>,
+    waiting: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex holding `value`.
+    pub fn new(value: T) -> Self {
+        Mutex {
+            locked: Cell::new(false),
+            waiting: WaitQueue::default(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Takes the value out of the mutex.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Locks the mutex, parking the running task while another one holds
+    /// it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        while self.locked.get() {
+            self.waiting.wait();
+        }
+        self.locked.set(true);
+        MutexGuard { mutex: self }
+    }
+
+    /// Locks the mutex if nobody holds it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.locked.get() {
+            None
+        } else {
+            self.locked.set(true);
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    /// The value, which cannot be locked while borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.locked.set(false);
+        self.waiting.notify_one();
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Access to the value of a locked `Mutex`, unlocking it when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A lock giving either any number of tasks shared access to `T`, or one
+/// task exclusive access. Tasks waiting for exclusive access keep new ones
+/// from getting shared access.
+pub struct RwLock<T: ?Sized> {
+    // number of readers, or -1 while written
+    state: Cell<isize>,
+    // tasks in `write`, which keep new readers out even once woken
+    writers: Cell<usize>,
+    reading: WaitQueue,
+    writing: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates an unlocked lock holding `value`.
+    pub fn new(value: T) -> Self {
+        RwLock {
+            state: Cell::new(0),
+            writers: Cell::new(0),
+            reading: WaitQueue::default(),
+            writing: WaitQueue::default(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Takes the value out of the lock.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks for shared access, parking the running task while another one
+    /// holds or waits for exclusive access.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        while !self.can_read() {
+            self.reading.wait();
+        }
+        self.state.set(self.state.get() + 1);
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Locks for shared access if that would not block.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if self.can_read() {
+            self.state.set(self.state.get() + 1);
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Locks for exclusive access, parking the running task while another
+    /// one holds any access.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.writers.set(self.writers.get() + 1);
+        let _writer = PendingWriter(self);
+        while self.state.get() != 0 {
+            self.writing.wait();
+        }
+        self.state.set(-1);
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Locks for exclusive access if nobody holds any.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if self.state.get() == 0 {
+            self.state.set(-1);
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// The value, which cannot be locked while borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn can_read(&self) -> bool {
+        self.state.get() >= 0 && self.writers.get() == 0
+    }
+
+    fn unlock_read(&self) {
+        self.state.set(self.state.get() - 1);
+        if self.state.get() == 0 && !self.writing.notify_one() {
+            self.reading.notify_all();
+        }
+    }
+
+    fn unlock_write(&self) {
+        self.state.set(0);
+        if !self.writing.notify_one() {
+            self.reading.notify_all();
+        }
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+// Counts a task out of `RwLock::write`. One unwound out of it, e.g. by
+// `timeout`, may leave the lock free with readers parked only for its sake,
+// so they are woken once no writer is left.
+struct PendingWriter<'a, T: ?Sized>(&'a RwLock<T>);
+
+impl<T: ?Sized> Drop for PendingWriter<'_, T> {
+    fn drop(&mut self) {
+        let lock = self.0;
+        lock.writers.set(lock.writers.get() - 1);
+        if lock.can_read() {
+            lock.reading.notify_all();
+        }
+    }
+}
+
+/// Shared access to the value of a `RwLock`, released when dropped.
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+/// Exclusive access to the value of a `RwLock`, released when dropped.
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}
+
+/// Lets tasks wait for a condition on the value of a `Mutex` to become
+/// true.
+#[derive(Default)]
+pub struct Condvar {
+    waiting: WaitQueue,
+}
+
+impl Condvar {
+    /// Creates a condition variable nobody waits on.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unlocks the mutex of `guard` and parks the running task until
+    /// notified, then locks the mutex again. The task may also wake up
+    /// without the condition being true, so check it again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from a task.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        drop(guard);
+        self.waiting.wait();
+        mutex.lock()
+    }
+
+    /// Like `wait`, until `condition` returns false for the value.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes the task which has waited longest.
+    pub fn notify_one(&self) {
+        self.waiting.notify_one();
+    }
+
+    /// Wakes every waiting task.
+    pub fn notify_all(&self) {
+        self.waiting.notify_all();
+    }
+}
+
+/// A number of permits, which tasks take and give back, parking while there
+/// are none left.
+pub struct Semaphore {
+    permits: Cell<usize>,
+    waiting: WaitQueue,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits.
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Cell::new(permits),
+            waiting: WaitQueue::default(),
+        }
+    }
+
+    /// Takes a permit, parking the running task until there is one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        while self.permits.get() == 0 {
+            self.waiting.wait();
+        }
+        self.permits.set(self.permits.get() - 1);
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// Takes a permit if there is one.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if self.permits.get() == 0 {
+            None
+        } else {
+            self.permits.set(self.permits.get() - 1);
+            Some(SemaphorePermit { semaphore: self })
+        }
+    }
+
+    /// Adds `n` permits.
+    pub fn add_permits(&self, n: usize) {
+        self.permits.set(self.permits.get() + n);
+        for _ in 0..n {
+            if !self.waiting.notify_one() {
+                break;
+            }
+        }
+    }
+
+    /// Number of permits not taken.
+    pub fn available_permits(&self) -> usize {
+        self.permits.get()
+    }
+}
+
+/// A permit taken from a `Semaphore`, given back when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit from being given back, so the semaphore has one less.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+/// Lets a number of tasks wait until all of them have got to it.
+pub struct Barrier {
+    count: usize,
+    arrived: Cell<usize>,
+    // bumped whenever all have arrived, so that waiters tell a new round
+    // from theirs
+    generation: Cell<u64>,
+    waiting: WaitQueue,
+}
+
+impl Barrier {
+    /// Creates a barrier for `count` tasks.
+    pub fn new(count: usize) -> Self {
+        Barrier {
+            count,
+            arrived: Cell::new(0),
+            generation: Cell::new(0),
+            waiting: WaitQueue::default(),
+        }
+    }
+
+    /// Parks the running task until `count` tasks have called `wait`, then
+    /// lets them all go on, and starts over. Returns whether the task was
+    /// the last one to arrive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn wait(&self) -> BarrierWaitResult {
+        let arrived = self.arrived.get() + 1;
+        if arrived >= self.count {
+            self.arrived.set(0);
+            self.generation.set(self.generation.get() + 1);
+            self.waiting.notify_all();
+            return BarrierWaitResult(true);
+        }
+        self.arrived.set(arrived);
+        let generation = self.generation.get();
+        let arrival = Arrival { barrier: self, generation };
+        while self.generation.get() == generation {
+            self.waiting.wait();
+        }
+        core::mem::forget(arrival);
+        BarrierWaitResult(false)
+    }
+}
+
+// Takes back the arrival of a task unwound out of `Barrier::wait`, e.g. by
+// `timeout`, unless its round got released meanwhile, so the next round
+// still needs `count` tasks.
+struct Arrival<'a> {
+    barrier: &'a Barrier,
+    generation: u64,
+}
+
+impl Drop for Arrival<'_> {
+    fn drop(&mut self) {
+        let barrier = self.barrier;
+        if barrier.generation.get() == self.generation {
+            barrier.arrived.set(barrier.arrived.get() - 1);
+        }
+    }
+}
+
+/// What `Barrier::wait` returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Whether the task was the last one to arrive at the barrier, which is
+    /// one of them each round.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+/// Lets tasks wait until a count of pending work, raised by `add` and
+/// lowered by `done`, gets down to 0.
+#[derive(Default)]
+pub struct WaitGroup {
+    count: Cell<usize>,
+    waiting: WaitQueue,
+}
+
+impl WaitGroup {
+    /// Creates a wait group with nothing pending.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `n` to the count.
+    pub fn add(&self, n: usize) {
+        self.count.set(self.count.get() + n);
+    }
+
+    /// Lowers the count by 1, waking the waiting tasks once it gets to 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the count is already 0.
+    pub fn done(&self) {
+        let count = self.count.get().checked_sub(1);
+        let count = count.expect("`WaitGroup::done` called more often than `add`");
+        self.count.set(count);
+        if count == 0 {
+            self.waiting.notify_all();
+        }
+    }
+
+    /// Parks the running task until the count is 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it would block outside of a task.
+    pub fn wait(&self) {
+        while self.count.get() != 0 {
+            self.waiting.wait();
+        }
+    }
+
+    /// The count.
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+}
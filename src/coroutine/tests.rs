@@ -0,0 +1,1555 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Mutex, TryLockError};
+
+use super::*;
+
+// what the coroutine panicked with, for a `try_resume` which must have failed
+// that way
+fn panic_payload<T: std::fmt::Debug>(result: Result<T, ResumeError>) -> PanicPayload {
+    match result {
+        Err(ResumeError::Panicked(payload)) => payload,
+        other => panic!("expected a panic, got {:?}", other),
+    }
+}
+
+fn fail_deep(depth: u32) -> u32 {
+    if depth == 0 {
+        panic!("deep failure");
+    }
+    fail_deep(depth - 1) + 1
+}
+
+#[test]
+fn panic_after_yields_is_returned_by_try_resume() {
+    let mut coro = Coroutine::with_yielder(|yielder: &Yielder<(), u32>, ()| {
+        for i in 0..3 {
+            yielder.yield_(i);
+        }
+        fail_deep(5)
+    });
+
+    for i in 0..3 {
+        assert_eq!(coro.try_resume(()).unwrap(), CoroutineResult::Yielded(i));
+    }
+    let payload = panic_payload(coro.try_resume(()));
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"deep failure"));
+    assert!(coro.is_finished());
+    assert!(coro.is_panicked());
+}
+
+#[test]
+fn panic_is_reraised_by_resume() {
+    let mut coro = Coroutine::new(|| {
+        yield_now();
+        yield_now();
+        fail_deep(3);
+    });
+    coro.resume(());
+    coro.resume(());
+
+    let payload = catch_unwind(AssertUnwindSafe(|| coro.resume(()))).unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"deep failure"));
+    assert!(coro.is_panicked());
+}
+
+#[test]
+fn panic_payload_is_preserved() {
+    let mut coro = Coroutine::with_yielder(|yielder: &Yielder<i32, ()>, mut input: i32| {
+        while input > 0 {
+            input = yielder.yield_(());
+        }
+        std::panic::panic_any(input)
+    });
+    coro.resume(2);
+    coro.resume(1);
+
+    let payload = panic_payload(coro.try_resume(-7));
+    assert_eq!(payload.downcast_ref::<i32>(), Some(&-7));
+}
+
+#[test]
+fn resume_reports_why_it_cannot_run() {
+    let mut done = Coroutine::new(|| {});
+    assert_eq!(done.try_resume(()).unwrap(), CoroutineResult::Complete(()));
+    assert!(matches!(done.try_resume(()), Err(ResumeError::AlreadyFinished)));
+    let payload = catch_unwind(AssertUnwindSafe(|| done.resume(()))).unwrap_err();
+    assert_eq!(
+        payload.downcast_ref::<String>().map(String::as_str),
+        Some("cannot resume coroutine: coroutine has already finished")
+    );
+
+    let mut failed = Coroutine::new(|| panic!("failed"));
+    panic_payload(failed.try_resume(()));
+    assert!(matches!(failed.try_resume(()), Err(ResumeError::Poisoned)));
+
+    // only reachable through aliasing, which is what the check guards against
+    let this: Cell<*mut Coroutine> = Cell::new(std::ptr::null_mut());
+    let refused = Cell::new(false);
+    let mut running = Coroutine::new(|| {
+        let this = unsafe { &mut *this.get() };
+        refused.set(matches!(this.try_resume(()), Err(ResumeError::Running)));
+    });
+    this.set(&mut running);
+    running.resume(());
+    assert!(refused.get());
+}
+
+#[test]
+fn yield_now_outside_of_a_coroutine_panics() {
+    assert!(!in_coroutine());
+    assert!(Coroutine::current().is_none());
+    let payload = catch_unwind(yield_now).unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"`yield_now` called outside of a coroutine"));
+}
+
+#[test]
+fn yield_now_in_a_coroutine_with_a_yielder_panics_in_it() {
+    let mut coro = Coroutine::with_yielder(|yielder: &Yielder<(), ()>, ()| {
+        let payload = catch_unwind(yield_now).unwrap_err();
+        // still running, the caller of `resume` never saw the attempt
+        yielder.yield_(());
+        payload.downcast_ref::<&str>().map(|s| s.to_string())
+    });
+    assert_eq!(coro.resume(()), CoroutineResult::Yielded(()));
+    assert_eq!(
+        coro.resume(()),
+        CoroutineResult::Complete(Some(
+            "`yield_now` can only suspend a coroutine created without a `Yielder`, use `Yielder::yield_`"
+                .to_string()
+        ))
+    );
+}
+
+#[test]
+fn current_describes_the_running_coroutine() {
+    let mut plain = CoroutineBuilder::new().name("plain").stack_size(64 * 1024).build(|| {
+        assert!(in_coroutine());
+        let current = Coroutine::current().unwrap();
+        assert_eq!(current.name(), Some("plain"));
+        assert_eq!(current.stack_size(), 64 * 1024);
+        assert!(current.can_yield_now());
+    });
+    plain.resume(());
+
+    let mut with_yielder = Coroutine::with_yielder(|_: &Yielder<(), u32>, ()| {
+        let current = Coroutine::current().unwrap();
+        current.name().is_none() && !current.can_yield_now()
+    });
+    assert_eq!(with_yielder.resume(()), CoroutineResult::Complete(true));
+}
+
+#[test]
+fn other_coroutines_keep_running_after_a_panic() {
+    let mut log = Vec::new();
+    let mut ok = Coroutine::with_yielder(|yielder: &Yielder<(), u32>, ()| {
+        for i in 0..4 {
+            yielder.yield_(i);
+        }
+    });
+    let mut bad = Coroutine::new(|| {
+        yield_now();
+        fail_deep(2);
+    });
+
+    bad.resume(());
+    while let CoroutineResult::Yielded(i) = ok.resume(()) {
+        log.push(i);
+        if !bad.is_finished() {
+            assert!(bad.try_resume(()).is_err());
+        }
+    }
+    assert_eq!(log, [0, 1, 2, 3]);
+}
+
+struct DropCounter<'a>(&'a std::cell::Cell<u32>);
+
+impl Drop for DropCounter<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test]
+fn drop_unwinds_suspended_coroutine() {
+    let drops = std::cell::Cell::new(0);
+    let lock = Mutex::new(());
+    let mut coro = Coroutine::new(|| {
+        let _outer = DropCounter(&drops);
+        let _boxed = Box::new(DropCounter(&drops));
+        let _guard = lock.lock().unwrap();
+        yield_now();
+        let _inner: Vec<_> = (0..2).map(|_| DropCounter(&drops)).collect();
+        yield_now();
+        unreachable!();
+    });
+    coro.resume(());
+    coro.resume(());
+    assert_eq!(drops.get(), 0);
+    assert!(matches!(lock.try_lock(), Err(TryLockError::WouldBlock)));
+
+    drop(coro);
+    assert_eq!(drops.get(), 4);
+    // the guard was dropped while unwinding, so the lock is poisoned but free
+    assert!(!matches!(lock.try_lock(), Err(TryLockError::WouldBlock)));
+}
+
+// yields from its destructor, which runs while the coroutine is dropped
+struct YieldOnDrop<'a, F: Fn()> {
+    yield_: F,
+    message: &'a std::cell::RefCell<Option<String>>,
+}
+
+impl<F: Fn()> Drop for YieldOnDrop<'_, F> {
+    fn drop(&mut self) {
+        let payload = catch_unwind(AssertUnwindSafe(&self.yield_)).unwrap_err();
+        *self.message.borrow_mut() = payload.downcast_ref::<&str>().map(|s| s.to_string());
+    }
+}
+
+#[test]
+fn yield_from_a_coroutine_being_dropped_panics() {
+    let message = std::cell::RefCell::new(None);
+    let drops = Cell::new(0);
+    let mut coro = Coroutine::with_yielder(|yielder: &Yielder<(), i32>, ()| {
+        let _counter = DropCounter(&drops);
+        let _guard = YieldOnDrop {
+            yield_: || {
+                yielder.yield_(7);
+            },
+            message: &message,
+        };
+        yielder.yield_(1);
+    });
+    assert_eq!(coro.resume(()), CoroutineResult::Yielded(1));
+    drop(coro);
+    assert_eq!(message.borrow().as_deref(), Some("cannot yield from a coroutine being dropped"));
+    // the unwinding went on past the destructor
+    assert_eq!(drops.get(), 1);
+
+    let message = std::cell::RefCell::new(None);
+    let mut coro = Coroutine::new(|| {
+        let _counter = DropCounter(&drops);
+        let _guard = YieldOnDrop {
+            yield_: yield_now,
+            message: &message,
+        };
+        yield_now();
+    });
+    coro.resume(());
+    drop(coro);
+    assert_eq!(message.borrow().as_deref(), Some("cannot yield from a coroutine being dropped"));
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn drop_releases_unstarted_coroutine() {
+    let drops = std::cell::Cell::new(0);
+    let counter = DropCounter(&drops);
+    let coro = Coroutine::new(move || {
+        let _counter = counter;
+        unreachable!();
+    });
+
+    drop(coro);
+    assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn leak_skips_unwinding() {
+    let drops = std::cell::Cell::new(0);
+    let mut coro = Coroutine::new(|| {
+        let _counter = DropCounter(&drops);
+        yield_now();
+    });
+    coro.resume(());
+
+    coro.leak();
+    assert_eq!(drops.get(), 0);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn threads_schedule_independently() {
+    const THREADS: usize = 16;
+    const COROS: usize = 32;
+    const YIELDS: usize = 200;
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            std::thread::spawn(move || {
+                let counts = vec![std::cell::Cell::new(0); COROS];
+                let scheduler = Scheduler::new();
+                for count in &counts {
+                    scheduler.spawn(move || {
+                        for _ in 0..YIELDS {
+                            count.set(count.get() + 1);
+                            yield_now();
+                        }
+                    });
+                }
+                scheduler.run();
+                drop(scheduler);
+                (thread, counts.iter().map(|c| c.get()).sum::<usize>())
+            })
+        })
+        .collect();
+
+    for (index, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), (index, COROS * YIELDS));
+    }
+}
+
+#[test]
+fn threads_pass_values_independently() {
+    let handles: Vec<_> = (0..16u64)
+        .map(|thread| {
+            std::thread::spawn(move || {
+                let mut coro = Coroutine::with_yielder(|yielder: &Yielder<u64, u64>, mut n| {
+                    loop {
+                        n = yielder.yield_(n * 2 + thread);
+                    }
+                });
+                for n in 0..10_000 {
+                    assert_eq!(coro.resume(n), CoroutineResult::Yielded(n * 2 + thread));
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// yields `0..n` from a chain of `depth` generators, each passing on what the
+// one it resumes yields
+fn nested_counter<'a>(depth: u32, n: u32) -> Coroutine<'a, (), u32> {
+    if depth == 0 {
+        return Coroutine::with_yielder(move |yielder: &Yielder<(), u32>, ()| {
+            for i in 0..n {
+                yielder.yield_(i);
+            }
+        });
+    }
+    Coroutine::with_yielder(move |yielder: &Yielder<(), u32>, ()| {
+        let mut inner = nested_counter(depth - 1, n);
+        while let CoroutineResult::Yielded(i) = inner.resume(()) {
+            yielder.yield_(i);
+        }
+    })
+}
+
+#[test]
+fn generators_consume_nested_generators() {
+    let mut outer = nested_counter(64, 10);
+    let mut seen = Vec::new();
+    while let CoroutineResult::Yielded(i) = outer.resume(()) {
+        seen.push(i);
+    }
+    assert_eq!(seen, (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn nested_coroutine_returns_to_its_resumer() {
+    let log = std::cell::RefCell::new(Vec::new());
+    let mut outer = Coroutine::new(|| {
+        let mut inner = Coroutine::new(|| {
+            log.borrow_mut().push("inner 1");
+            yield_now();
+            log.borrow_mut().push("inner 2");
+        });
+        inner.resume(());
+        log.borrow_mut().push("outer 1");
+        yield_now();
+        inner.resume(());
+        log.borrow_mut().push("outer 2");
+    });
+
+    outer.resume(());
+    log.borrow_mut().push("main");
+    outer.resume(());
+    assert!(outer.is_finished());
+    assert_eq!(
+        *log.borrow(),
+        ["inner 1", "outer 1", "main", "inner 2", "outer 2"]
+    );
+}
+
+#[test]
+fn yielder_of_resumer_cannot_be_used_by_nested_coroutine() {
+    let mut outer = Coroutine::with_yielder(|yielder: &Yielder<(), u32>, ()| {
+        let mut inner = Coroutine::new(|| {
+            yielder.yield_(1);
+        });
+        inner.try_resume(()).is_err()
+    });
+    assert_eq!(outer.resume(()), CoroutineResult::Complete(true));
+}
+
+#[test]
+fn builder_sets_name() {
+    let coro = CoroutineBuilder::new().name("worker").build(|| {});
+    assert_eq!(coro.name(), Some("worker"));
+    assert_eq!(Coroutine::new(|| {}).name(), None);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn many_coroutines_with_small_stacks() {
+    let count = std::cell::Cell::new(0);
+    let scheduler = Scheduler::new();
+    for _ in 0..10_000 {
+        scheduler.spawn_with(CoroutineBuilder::new().stack_size(16 * 1024), || {
+            for _ in 0..3 {
+                count.set(count.get() + 1);
+                yield_now();
+            }
+        });
+    }
+    scheduler.run();
+    assert_eq!(count.get(), 30_000);
+}
+
+#[derive(Default)]
+struct CountingAllocator {
+    live: Rc<std::cell::Cell<usize>>,
+    sizes: Rc<std::cell::RefCell<Vec<usize>>>,
+}
+
+unsafe impl StackAllocator for CountingAllocator {
+    fn allocate(&self, layout: std::alloc::Layout) -> *mut u8 {
+        self.live.set(self.live.get() + 1);
+        self.sizes.borrow_mut().push(layout.size());
+        HeapStackAllocator.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, address: *mut u8, layout: std::alloc::Layout) {
+        self.live.set(self.live.get() - 1);
+        HeapStackAllocator.deallocate(address, layout)
+    }
+}
+
+#[test]
+fn builder_uses_stack_allocator() {
+    let allocator = CountingAllocator::default();
+    let (live, sizes) = (allocator.live.clone(), allocator.sizes.clone());
+    let builder = CoroutineBuilder::new()
+        .stack_size(100_001)
+        .stack_allocator(allocator);
+    let mut coro = builder.build_with_yielder(|yielder: &Yielder<(), u32>, ()| {
+        yielder.yield_(1);
+        2
+    });
+    assert_eq!(live.get(), 1);
+    assert_eq!(*sizes.borrow(), [100_016]);
+
+    assert_eq!(coro.resume(()), CoroutineResult::Yielded(1));
+    assert_eq!(coro.resume(()), CoroutineResult::Complete(2));
+    drop(coro);
+    assert_eq!(live.get(), 0);
+}
+
+#[test]
+fn stack_size_has_a_minimum() {
+    let allocator = CountingAllocator::default();
+    let sizes = allocator.sizes.clone();
+    let coro = CoroutineBuilder::new()
+        .stack_size(0)
+        .stack_allocator(allocator)
+        .build(|| {});
+    drop(coro);
+    assert_eq!(*sizes.borrow(), [CoroutineBuilder::MIN_STACK_SIZE]);
+}
+
+#[cfg(all(unix, feature = "guard-pages"))]
+#[test]
+fn stack_overflow_is_reported() {
+    // the overflow aborts, so it happens in a copy of this test binary
+    const CHILD_ENV: &str = "STACKFUL_COROUTINE_OVERFLOW_CHILD";
+
+    fn recurse(depth: u64) -> u64 {
+        if depth == u64::MAX {
+            return 0;
+        }
+        let frame = std::hint::black_box([depth; 64]);
+        recurse(depth + 1) + frame[0]
+    }
+
+    if std::env::var_os(CHILD_ENV).is_some() {
+        let mut coro = CoroutineBuilder::new()
+            .name("deep")
+            .stack_size(64 * 1024)
+            .build(|| {
+                recurse(0);
+            });
+        coro.resume(());
+        unreachable!();
+    }
+
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "coroutine::tests::stack_overflow_is_reported"])
+        .args(["--nocapture", "--test-threads=1"])
+        .env(CHILD_ENV, "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("coroutine 'deep' overflowed its 65536-byte stack"),
+        "unexpected stderr: {}",
+        stderr
+    );
+}
+
+fn pooled(pool: &Rc<StackPool>, size: usize) -> Coroutine<'static> {
+    CoroutineBuilder::new()
+        .stack_size(size)
+        .stack_allocator(pool.clone())
+        .build(yield_now)
+}
+
+#[test]
+fn stack_pool_recycles_stacks() {
+    let allocator = CountingAllocator::default();
+    let sizes = allocator.sizes.clone();
+    let pool = Rc::new(StackPool::with_size_classes(allocator, &[32 * 1024, 128 * 1024]));
+
+    let mut first = pooled(&pool, 20 * 1024);
+    first.resume(());
+    drop(first);
+    assert_eq!(pool.retained(), 1);
+
+    // served from the retained 32 KiB stack
+    let second = pooled(&pool, 30 * 1024);
+    assert_eq!(pool.retained(), 0);
+    drop(second);
+    // a new 128 KiB one, and one bypassing the pool
+    drop(pooled(&pool, 100 * 1024));
+    drop(pooled(&pool, 1024 * 1024));
+    assert_eq!(pool.retained(), 2);
+    assert_eq!(*sizes.borrow(), [32 * 1024, 128 * 1024, 1024 * 1024]);
+}
+
+#[test]
+fn stack_pool_keeps_at_most_max_retained() {
+    let allocator = CountingAllocator::default();
+    let live = allocator.live.clone();
+    let pool = Rc::new(StackPool::with_size_classes(allocator, &[64 * 1024]));
+    pool.set_max_retained(3);
+
+    let coros: Vec<_> = (0..5).map(|_| pooled(&pool, 64 * 1024)).collect();
+    assert_eq!(live.get(), 5);
+    drop(coros);
+    assert_eq!(pool.retained(), 3);
+    assert_eq!(live.get(), 3);
+
+    pool.set_max_retained(1);
+    assert_eq!(live.get(), 1);
+    drop(pool);
+    assert_eq!(live.get(), 0);
+}
+
+// cargo test --release -- --ignored --nocapture bench_
+#[test]
+#[ignore]
+fn bench_stack_pool_create_destroy() {
+    const ROUNDS: u32 = 20_000;
+
+    fn run(name: &str, mut build: impl FnMut() -> Coroutine<'static>) {
+        let start = std::time::Instant::now();
+        for _ in 0..ROUNDS {
+            let mut coro = build();
+            coro.resume(());
+            drop(coro);
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{:>8}: {:>10.0} coroutines/s",
+            name,
+            f64::from(ROUNDS) / elapsed.as_secs_f64()
+        );
+    }
+
+    for size in [64 * 1024, CoroutineBuilder::DEFAULT_STACK_SIZE] {
+        println!("stack size {} bytes", size);
+        run("unpooled", || {
+            CoroutineBuilder::new()
+                .stack_size(size)
+                .stack_allocator(DefaultStackAllocator::default())
+                .build(yield_now)
+        });
+        run("pooled", || CoroutineBuilder::new().stack_size(size).build(yield_now));
+    }
+}
+
+fn touch_stack(bytes: usize) {
+    let mut frame = [0u8; 16 * 1024];
+    frame[0] = 1;
+    std::hint::black_box(&mut frame);
+    if bytes > frame.len() {
+        touch_stack(bytes - frame.len());
+    }
+}
+
+#[test]
+fn peak_stack_usage_measures_high_water_mark() {
+    let mut coro = CoroutineBuilder::new()
+        .stack_size(1024 * 1024)
+        .measure_stack_usage(true)
+        .build(|| {
+            yield_now();
+            touch_stack(200 * 1024);
+            yield_now();
+        });
+    // only what `Context::new` put at the top to start with
+    assert!(coro.peak_stack_usage().unwrap() < 64);
+
+    coro.resume(());
+    let shallow = coro.peak_stack_usage().unwrap();
+    assert!((64..64 * 1024).contains(&shallow), "{}", shallow);
+
+    coro.resume(());
+    let deep = coro.peak_stack_usage().unwrap();
+    assert!((200 * 1024..1024 * 1024).contains(&deep), "{}", deep);
+    // the peak stays after the stack unwinds
+    coro.resume(());
+    assert!(coro.is_finished());
+    assert_eq!(coro.peak_stack_usage(), Some(deep));
+}
+
+#[test]
+fn stack_usage_is_measured_at_suspension() {
+    fn suspend_at_depth(depth: u32) {
+        let frame = std::hint::black_box([0u8; 1024]);
+        if depth == 0 {
+            yield_now();
+        } else {
+            suspend_at_depth(depth - 1);
+        }
+        std::hint::black_box(frame);
+    }
+
+    let mut coro = Coroutine::new(|| {
+        suspend_at_depth(0);
+        suspend_at_depth(100);
+    });
+    assert_eq!(coro.peak_stack_usage(), None);
+
+    coro.resume(());
+    let shallow = coro.stack_usage();
+    coro.resume(());
+    let deep = coro.stack_usage();
+    assert!(deep >= shallow + 100 * 1024, "{} {}", shallow, deep);
+    coro.resume(());
+    assert_eq!(coro.stack_usage(), 0);
+}
+
+#[test]
+fn transfer_runs_other_in_place() {
+    let log = std::cell::RefCell::new(Vec::new());
+    let mut b = CoroutineBuilder::new().name("b").build(|| {
+        assert_eq!(current_coroutine_name(), Some("b"));
+        log.borrow_mut().push("b1");
+        yield_now();
+        log.borrow_mut().push("b2");
+    });
+    let mut a = CoroutineBuilder::new().name("a").build(|| {
+        log.borrow_mut().push("a1");
+        assert_eq!(b.transfer_to(()), Transfer::Resumed(()));
+        log.borrow_mut().push("a2");
+        assert_eq!(b.transfer_to(()), Transfer::Returned(()));
+        assert_eq!(current_coroutine_name(), Some("a"));
+        log.borrow_mut().push("a3");
+    });
+
+    // `b` yields straight to us, and the next resume continues `a`
+    assert_eq!(a.resume(()), CoroutineResult::Yielded(()));
+    assert_eq!(*log.borrow(), ["a1", "b1"]);
+    assert_eq!(a.resume(()), CoroutineResult::Complete(()));
+    assert_eq!(*log.borrow(), ["a1", "b1", "a2", "b2", "a3"]);
+}
+
+#[test]
+fn transfer_passes_values() {
+    let mut double = Coroutine::with_yielder(|yielder: &Yielder<i32, i32>, mut input| {
+        while input >= 0 {
+            input = yielder.yield_(input * 2);
+        }
+        "done"
+    });
+    let mut relay = Coroutine::with_yielder(|yielder: &Yielder<i32, i32>, mut input| loop {
+        match yielder.transfer_to(&mut double, input) {
+            Transfer::Resumed(next) => input = next,
+            Transfer::Returned(s) => return s.len() as i32,
+        }
+    });
+
+    assert_eq!(relay.resume(1), CoroutineResult::Yielded(2));
+    assert_eq!(relay.resume(5), CoroutineResult::Yielded(10));
+    assert_eq!(relay.resume(-1), CoroutineResult::Complete(4));
+}
+
+#[test]
+fn transfer_chain_returns_to_each_transferrer() {
+    let log = std::cell::RefCell::new(Vec::new());
+    let mut c = Coroutine::new(|| {
+        log.borrow_mut().push("c1");
+        yield_now();
+        log.borrow_mut().push("c2");
+    });
+    let mut b = Coroutine::new(|| {
+        assert_eq!(c.transfer_to(()), Transfer::Resumed(()));
+        log.borrow_mut().push("b1");
+        assert_eq!(c.transfer_to(()), Transfer::Returned(()));
+        log.borrow_mut().push("b2");
+    });
+    let mut a = Coroutine::new(|| {
+        assert_eq!(b.transfer_to(()), Transfer::Resumed(()));
+        log.borrow_mut().push("a1");
+        assert_eq!(b.transfer_to(()), Transfer::Returned(()));
+        log.borrow_mut().push("a2");
+    });
+
+    assert_eq!(a.resume(()), CoroutineResult::Yielded(()));
+    assert_eq!(a.resume(()), CoroutineResult::Complete(()));
+    assert_eq!(*log.borrow(), ["c1", "a1", "b1", "c2", "b2", "a2"]);
+}
+
+#[test]
+fn transfer_reraises_panic_of_other() {
+    let mut b = Coroutine::new(|| panic!("b failed"));
+    let mut a = Coroutine::new(|| {
+        b.transfer_to(());
+    });
+
+    let payload = panic_payload(a.try_resume(()));
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"b failed"));
+    assert!(a.is_panicked());
+    drop(a);
+    assert!(b.is_panicked());
+}
+
+#[test]
+fn transfer_requires_a_unit_coroutine() {
+    let mut b = Coroutine::new(|| {});
+    let result = catch_unwind(AssertUnwindSafe(|| b.transfer_to(())));
+    assert!(result.is_err());
+
+    let mut a = Coroutine::with_yielder(|_: &Yielder<(), i32>, ()| {
+        b.transfer_to(());
+    });
+    assert!(a.try_resume(()).is_err());
+    drop(a);
+    assert!(!b.is_finished());
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod fp_control {
+    use core::arch::asm;
+
+    // rounding control bits of MXCSR and of the x87 control word
+    pub const MXCSR_ROUNDING: u32 = 0b11 << 13;
+    pub const X87_ROUNDING: u16 = 0b11 << 10;
+    // round towards +inf and towards -inf, in the position of either
+    pub const UP: u32 = 0b10;
+    pub const DOWN: u32 = 0b01;
+
+    pub fn mxcsr() -> u32 {
+        let mut mxcsr = 0u32;
+        unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr) };
+        mxcsr
+    }
+
+    pub fn x87_control_word() -> u16 {
+        let mut cw = 0u16;
+        unsafe { asm!("fnstcw [{}]", in(reg) &mut cw) };
+        cw
+    }
+
+    // sets the rounding mode of both SSE and x87 arithmetic
+    pub fn set_rounding(mode: u32) {
+        let mxcsr = mxcsr() & !MXCSR_ROUNDING | mode << 13;
+        let cw = x87_control_word() & !X87_ROUNDING | (mode as u16) << 10;
+        unsafe {
+            asm!("ldmxcsr [{}]", in(reg) &mxcsr);
+            asm!("fldcw [{}]", in(reg) &cw);
+        }
+    }
+
+    pub fn rounding() -> (u32, u32) {
+        (
+            (mxcsr() & MXCSR_ROUNDING) >> 13,
+            ((x87_control_word() & X87_ROUNDING) >> 10) as u32,
+        )
+    }
+
+    pub fn third() -> f64 {
+        std::hint::black_box(1.0f64) / std::hint::black_box(3.0f64)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[test]
+fn rounding_mode_stays_with_its_coroutine() {
+    use fp_control::*;
+
+    let main_rounding = rounding();
+    let main_third = third();
+    let up_third = std::cell::Cell::new(0.0);
+    let down_third = std::cell::Cell::new(0.0);
+
+    let mut up = Coroutine::new(|| {
+        set_rounding(UP);
+        for _ in 0..3 {
+            yield_now();
+            assert_eq!(rounding(), (UP, UP));
+            up_third.set(third());
+        }
+    });
+    let mut down = Coroutine::new(|| {
+        set_rounding(DOWN);
+        for _ in 0..3 {
+            yield_now();
+            assert_eq!(rounding(), (DOWN, DOWN));
+            down_third.set(third());
+        }
+    });
+
+    while !up.is_finished() || !down.is_finished() {
+        up.resume(());
+        assert_eq!(rounding(), main_rounding);
+        down.resume(());
+        assert_eq!(rounding(), main_rounding);
+        assert_eq!(third(), main_third);
+    }
+    assert!(down_third.get() < up_third.get());
+}
+
+#[repr(C, align(16))]
+struct AlignedBuffer([u8; 64 * 1024]);
+
+#[test]
+fn coroutine_runs_on_provided_stack() {
+    let mut buffer = Box::new(AlignedBuffer([0; 64 * 1024]));
+    let range = buffer.0.as_ptr_range();
+    let range = range.start as usize..range.end as usize;
+    let on_stack = Cell::new(0);
+
+    let mut coro = Coroutine::with_stack(&mut buffer.0, || {
+        let local = 0u8;
+        on_stack.set(&local as *const u8 as usize);
+        yield_now();
+    });
+    coro.resume(());
+    assert!(range.contains(&on_stack.get()));
+    assert!(coro.stack_usage() > 0);
+    coro.resume(());
+    assert!(coro.is_finished());
+    drop(coro);
+
+    // the buffer is ours again
+    buffer.0.fill(0);
+}
+
+// a stack holding its memory by value
+struct InlineStack(AlignedBuffer);
+
+unsafe impl Stack for InlineStack {
+    fn base(&mut self) -> *mut u8 {
+        self.0 .0.as_mut_ptr()
+    }
+
+    fn size(&self) -> usize {
+        self.0 .0.len()
+    }
+}
+
+#[test]
+fn coroutine_runs_on_stack_held_by_value() {
+    let caller = 0u8;
+    let caller = &caller as *const u8 as usize;
+    let on_stack = Cell::new(0);
+    let mut coro = CoroutineBuilder::new().build_on(
+        InlineStack(AlignedBuffer([0; 64 * 1024])),
+        || {
+            let local = 0u8;
+            on_stack.set(&local as *const u8 as usize);
+            yield_now();
+            7
+        },
+    );
+    assert_eq!(coro.resume(()), CoroutineResult::Yielded(()));
+    // not on the moved-from value in the frame of the caller
+    assert!(on_stack.get().abs_diff(caller) > 64 * 1024);
+    assert!(coro.stack_usage() > 0);
+    assert_eq!(coro.resume(()), CoroutineResult::Complete(7));
+}
+
+#[test]
+fn builder_runs_on_owned_stack() {
+    // malloc aligns blocks to 16 bytes
+    let stack = vec![0u8; 64 * 1024].into_boxed_slice();
+    let mut coro = CoroutineBuilder::new()
+        .measure_stack_usage(true)
+        .build_with_yielder_on(stack, |yielder: &Yielder<u32, u32>, input| {
+            yielder.yield_(input + 1) * 2
+        });
+    assert_eq!(coro.resume(1), CoroutineResult::Yielded(2));
+    assert_eq!(coro.resume(5), CoroutineResult::Complete(10));
+    assert!(coro.peak_stack_usage().unwrap() > 0);
+}
+
+#[test]
+fn provided_stack_is_validated() {
+    let mut buffer = Box::new(AlignedBuffer([0; 64 * 1024]));
+    let misaligned = catch_unwind(AssertUnwindSafe(|| {
+        Coroutine::with_stack(&mut buffer.0[1..], || {});
+    }));
+    let message = misaligned.unwrap_err();
+    assert!(message.downcast_ref::<String>().unwrap().contains("not aligned"));
+
+    let small = catch_unwind(AssertUnwindSafe(|| {
+        Coroutine::with_stack(&mut buffer.0[..4096], || {});
+    }));
+    let message = small.unwrap_err();
+    assert!(message.downcast_ref::<String>().unwrap().contains("smaller than the minimum"));
+}
+
+#[test]
+fn raw_contexts_switch_back_and_forth() {
+    let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let main = Box::into_raw(Box::new(RawContext::main()));
+    let other = Rc::new(Cell::new(core::ptr::null_mut::<RawContext>()));
+
+    let stack = vec![0u8; 64 * 1024].into_boxed_slice();
+    let raw = RawContext::new(stack, {
+        let log = log.clone();
+        let other = other.clone();
+        move || loop {
+            log.borrow_mut().push("raw");
+            assert!(!in_coroutine());
+            unsafe { RawContext::switch(other.get(), main) };
+        }
+    });
+    other.set(Box::into_raw(Box::new(raw)));
+    for _ in 0..2 {
+        log.borrow_mut().push("main");
+        unsafe { RawContext::switch(main, other.get()) };
+    }
+    assert_eq!(*log.borrow(), ["main", "raw", "main", "raw"]);
+
+    unsafe {
+        drop(Box::from_raw(other.get()));
+        drop(Box::from_raw(main));
+    }
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn tasks_spawned_while_running_are_run() {
+    let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let scheduler = Scheduler::new();
+    let outer_log = log.clone();
+    scheduler.spawn(move || {
+        for i in 0..3 {
+            let log = outer_log.clone();
+            spawn(move || log.borrow_mut().push(i));
+            outer_log.borrow_mut().push(10 + i);
+            yield_now();
+        }
+    });
+    scheduler.run();
+    assert_eq!(*log.borrow(), [10, 0, 11, 1, 12, 2]);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn parked_task_is_not_resumed_until_unparked() {
+    let resumes = Cell::new(0);
+    let waiter: std::cell::RefCell<Option<scheduler::Task>> = Default::default();
+    let scheduler = Scheduler::new();
+    let parked = scheduler.spawn(|| {
+        *waiter.borrow_mut() = Some(scheduler::current());
+        resumes.set(resumes.get() + 1);
+        scheduler::park();
+        resumes.set(resumes.get() + 1);
+    });
+    scheduler.spawn(|| {
+        for _ in 0..10 {
+            yield_now();
+        }
+        assert_eq!(resumes.get(), 1);
+        waiter.borrow().as_ref().unwrap().unpark();
+    });
+    scheduler.run();
+    assert_eq!(resumes.get(), 2);
+    assert!(parked.is_finished());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn unpark_before_park_is_not_lost() {
+    let scheduler = Scheduler::new();
+    let task = scheduler.spawn(|| {
+        scheduler::current().unpark();
+        scheduler::park();
+    });
+    scheduler.run();
+    assert!(task.is_finished());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn run_returns_once_every_task_is_parked() {
+    let drops = Cell::new(0);
+    let scheduler = Scheduler::new();
+    let handle = scheduler.spawn(|| {
+        let _guard = DropCounter(&drops);
+        scheduler::park();
+        scheduler::park();
+    });
+    scheduler.run();
+    assert!(!handle.is_finished());
+
+    handle.task().unpark();
+    scheduler.run();
+    assert!(!handle.is_finished());
+    assert_eq!(drops.get(), 0);
+
+    drop(scheduler);
+    assert_eq!(drops.get(), 1);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn park_outside_of_a_task_panics() {
+    assert!(catch_unwind(scheduler::park).is_err());
+    let scheduler = Scheduler::new();
+    scheduler.spawn(|| {
+        let mut nested = Coroutine::new(scheduler::park);
+        assert!(nested.try_resume(()).is_err());
+    });
+    scheduler.run();
+}
+
+#[test]
+fn coroutine_without_yielder_returns_a_value() {
+    let mut coro = Coroutine::new(|| {
+        yield_now();
+        "done"
+    });
+    assert_eq!(coro.resume(()), CoroutineResult::Yielded(()));
+    assert_eq!(coro.resume(()), CoroutineResult::Complete("done"));
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn join_waits_for_the_task_to_return() {
+    let scheduler = Scheduler::new();
+    let sum = scheduler.spawn(|| {
+        let parts: Vec<_> = (1..=4)
+            .map(|i| {
+                spawn(move || {
+                    for _ in 0..i {
+                        yield_now();
+                    }
+                    i * 10
+                })
+            })
+            .collect();
+        parts.into_iter().map(|part| part.join().unwrap()).sum::<i32>()
+    });
+    scheduler.run();
+    assert!(sum.is_finished());
+    assert_eq!(sum.join().unwrap(), 100);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn join_returns_the_panic_of_the_task() {
+    let scheduler = Scheduler::new();
+    let failed = scheduler.spawn(|| -> u32 { panic!("task failed") });
+    let observer = scheduler.spawn(move || {
+        let payload = failed.join().unwrap_err();
+        *payload.downcast::<&str>().unwrap()
+    });
+    scheduler.run();
+    assert_eq!(observer.join().unwrap(), "task failed");
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn join_of_an_unfinished_task_outside_of_a_task_panics() {
+    let scheduler = Scheduler::new();
+    let task = scheduler.spawn(|| {});
+    assert!(catch_unwind(AssertUnwindSafe(|| task.join())).is_err());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn channel_carries_messages_from_producers_to_consumer() {
+    let scheduler = Scheduler::new();
+    let (tx, rx) = channel::channel();
+    for producer in 0..3 {
+        let tx = tx.clone();
+        scheduler.spawn(move || {
+            for i in 0..4 {
+                tx.send(producer * 10 + i).unwrap();
+                yield_now();
+            }
+        });
+    }
+    drop(tx);
+    let consumer = scheduler.spawn(move || rx.iter().collect::<Vec<_>>());
+    scheduler.run();
+
+    let mut received = consumer.join().unwrap();
+    assert_eq!(received.len(), 12);
+    received.sort();
+    assert_eq!(received, [0, 1, 2, 3, 10, 11, 12, 13, 20, 21, 22, 23]);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn sync_channel_blocks_sender_while_full() {
+    let log = std::cell::RefCell::new(Vec::new());
+    let (tx, rx) = channel::sync_channel(2);
+    let scheduler = Scheduler::new();
+    scheduler.spawn(|| {
+        for i in 0..4 {
+            tx.send(i).unwrap();
+            log.borrow_mut().push(format!("sent {}", i));
+        }
+    });
+    scheduler.spawn(|| {
+        for _ in 0..4 {
+            let i = rx.recv().unwrap();
+            log.borrow_mut().push(format!("received {}", i));
+        }
+    });
+    scheduler.run();
+    assert_eq!(
+        *log.borrow(),
+        ["sent 0", "sent 1", "received 0", "received 1", "sent 2", "sent 3", "received 2", "received 3"]
+    );
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn rendezvous_send_waits_for_the_receiver() {
+    let log = std::cell::RefCell::new(Vec::new());
+    let (tx, rx) = channel::sync_channel(0);
+    let scheduler = Scheduler::new();
+    assert!(matches!(tx.try_send(0), Err(channel::TrySendError::Full(0))));
+    scheduler.spawn(|| {
+        tx.send(1).unwrap();
+        log.borrow_mut().push("sent");
+    });
+    scheduler.spawn(|| {
+        for _ in 0..3 {
+            yield_now();
+        }
+        log.borrow_mut().push("receiving");
+        assert_eq!(rx.recv(), Ok(1));
+    });
+    scheduler.run();
+    assert_eq!(*log.borrow(), ["receiving", "sent"]);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn channel_reports_the_other_end_closing() {
+    let (tx, rx) = channel::channel::<i32>();
+    tx.send(1).unwrap();
+    drop(tx);
+    assert_eq!(rx.try_recv(), Ok(1));
+    assert_eq!(rx.try_recv(), Err(channel::TryRecvError::Disconnected));
+
+    let (tx, rx) = channel::channel();
+    drop(rx);
+    assert_eq!(tx.send(2), Err(channel::SendError(2)));
+
+    // a sender blocked on a full buffer is woken by the receiver going away
+    let scheduler = Scheduler::new();
+    let (tx, rx) = channel::sync_channel(1);
+    let sender = scheduler.spawn(move || (tx.send(1), tx.send(2)));
+    scheduler.spawn(move || {
+        yield_now();
+        drop(rx);
+    });
+    scheduler.run();
+    assert_eq!(sender.join().unwrap(), (Ok(()), Err(channel::SendError(2))));
+
+    // and a receiver blocked on an empty one by the last sender going away
+    let (tx, rx) = channel::sync_channel::<i32>(1);
+    let receiver = scheduler.spawn(move || rx.recv());
+    scheduler.spawn(move || drop(tx));
+    scheduler.run();
+    assert_eq!(receiver.join().unwrap(), Err(channel::RecvError));
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn oneshot_delivers_one_message() {
+    let scheduler = Scheduler::new();
+    let (tx, rx) = channel::oneshot();
+    let receiver = scheduler.spawn(move || rx.recv());
+    scheduler.spawn(move || {
+        yield_now();
+        tx.send("hello").unwrap();
+    });
+    scheduler.run();
+    assert_eq!(receiver.join().unwrap(), Ok("hello"));
+
+    let (tx, rx) = channel::oneshot::<()>();
+    let receiver = scheduler.spawn(move || rx.recv());
+    scheduler.spawn(move || drop(tx));
+    scheduler.run();
+    assert_eq!(receiver.join().unwrap(), Err(channel::RecvError));
+
+    let (tx, mut rx) = channel::oneshot();
+    assert_eq!(rx.try_recv(), Err(channel::TryRecvError::Empty));
+    drop(rx);
+    assert_eq!(tx.send(1), Err(channel::SendError(1)));
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn blocking_recv_outside_of_a_task_panics() {
+    let (_tx, rx) = channel::channel::<()>();
+    assert!(catch_unwind(AssertUnwindSafe(|| rx.recv())).is_err());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn mutex_held_across_a_yield_parks_the_others() {
+    let mutex = sync::Mutex::new(Vec::new());
+    let scheduler = Scheduler::new();
+    for task in 0..3 {
+        let mutex = &mutex;
+        scheduler.spawn(move || {
+            let mut log = mutex.lock();
+            log.push(task);
+            yield_now();
+            log.push(task);
+        });
+    }
+    scheduler.run();
+    drop(scheduler);
+    assert_eq!(mutex.into_inner(), [0, 0, 1, 1, 2, 2]);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn rwlock_shares_reads_and_excludes_writes() {
+    let lock = sync::RwLock::new(0);
+    let log = std::cell::RefCell::new(Vec::new());
+    let scheduler = Scheduler::new();
+    for reader in 0..2 {
+        let (lock, log) = (&lock, &log);
+        scheduler.spawn(move || {
+            let value = lock.read();
+            log.borrow_mut().push(format!("reader {} sees {}", reader, *value));
+            yield_now();
+            log.borrow_mut().push(format!("reader {} done", reader));
+        });
+    }
+    scheduler.spawn(|| {
+        *lock.write() += 1;
+        log.borrow_mut().push("written".to_string());
+    });
+    // waits behind the writer rather than joining the first readers
+    scheduler.spawn(|| {
+        let value = lock.read();
+        log.borrow_mut().push(format!("late reader sees {}", *value));
+    });
+    scheduler.run();
+    assert_eq!(
+        *log.borrow(),
+        [
+            "reader 0 sees 0",
+            "reader 1 sees 0",
+            "reader 0 done",
+            "reader 1 done",
+            "written",
+            "late reader sees 1"
+        ]
+    );
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn condvar_wakes_the_waiting_task() {
+    let ready = sync::Mutex::new(false);
+    let condvar = sync::Condvar::new();
+    let scheduler = Scheduler::new();
+    let waiter = scheduler.spawn(|| {
+        let ready = condvar.wait_while(ready.lock(), |ready| !*ready);
+        *ready
+    });
+    scheduler.spawn(|| {
+        yield_now();
+        *ready.lock() = true;
+        condvar.notify_one();
+    });
+    scheduler.run();
+    assert!(waiter.join().unwrap());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn semaphore_limits_how_many_run_at_once() {
+    let semaphore = sync::Semaphore::new(2);
+    let running = Cell::new(0);
+    let most = Cell::new(0);
+    let scheduler = Scheduler::new();
+    for _ in 0..6 {
+        scheduler.spawn(|| {
+            let _permit = semaphore.acquire();
+            running.set(running.get() + 1);
+            most.set(most.get().max(running.get()));
+            yield_now();
+            running.set(running.get() - 1);
+        });
+    }
+    scheduler.run();
+    assert_eq!(most.get(), 2);
+    assert_eq!(semaphore.available_permits(), 2);
+    semaphore.try_acquire().unwrap().forget();
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn barrier_releases_tasks_together() {
+    let barrier = sync::Barrier::new(3);
+    let log = std::cell::RefCell::new(Vec::new());
+    let leaders = Cell::new(0);
+    let scheduler = Scheduler::new();
+    for task in 0..3 {
+        let (barrier, log, leaders) = (&barrier, &log, &leaders);
+        scheduler.spawn(move || {
+            for round in 0..2 {
+                for _ in 0..task {
+                    yield_now();
+                }
+                log.borrow_mut().push(round);
+                if barrier.wait().is_leader() {
+                    leaders.set(leaders.get() + 1);
+                }
+            }
+        });
+    }
+    scheduler.run();
+    assert_eq!(*log.borrow(), [0, 0, 0, 1, 1, 1]);
+    assert_eq!(leaders.get(), 2);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn barrier_forgets_timed_out_waiters() {
+    use std::time::Duration;
+    let barrier = sync::Barrier::new(2);
+    let scheduler = Scheduler::new();
+    let timed_out = scheduler.spawn(|| timeout(Duration::from_millis(10), || barrier.wait()));
+    let late = scheduler.spawn(|| {
+        sleep(Duration::from_millis(20));
+        barrier.wait()
+    });
+    scheduler.run();
+    assert!(matches!(timed_out.join().unwrap(), Err(Elapsed { .. })));
+    assert!(!late.is_finished());
+    let last = scheduler.spawn(|| barrier.wait());
+    scheduler.run();
+    assert!(!late.join().unwrap().is_leader());
+    assert!(last.join().unwrap().is_leader());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn wait_group_waits_for_every_task() {
+    let group = sync::WaitGroup::new();
+    let finished = Cell::new(0);
+    let scheduler = Scheduler::new();
+    group.add(3);
+    for task in 0..3 {
+        let (group, finished) = (&group, &finished);
+        scheduler.spawn(move || {
+            for _ in 0..task {
+                yield_now();
+            }
+            finished.set(finished.get() + 1);
+            group.done();
+        });
+    }
+    let waiter = scheduler.spawn(|| {
+        group.wait();
+        finished.get()
+    });
+    scheduler.run();
+    assert_eq!(waiter.join().unwrap(), 3);
+    assert_eq!(group.count(), 0);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn sleeping_tasks_wake_in_deadline_order() {
+    use std::time::{Duration, Instant};
+
+    let log = std::cell::RefCell::new(Vec::new());
+    let scheduler = Scheduler::new();
+    for millis in [30, 10, 20] {
+        let log = &log;
+        scheduler.spawn(move || {
+            sleep(Duration::from_millis(millis));
+            log.borrow_mut().push(millis);
+        });
+    }
+    let start = Instant::now();
+    scheduler.run();
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    assert_eq!(*log.borrow(), [10, 20, 30]);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn sleep_until_lets_other_tasks_run() {
+    use std::time::{Duration, Instant};
+
+    let deadline = Instant::now() + Duration::from_millis(20);
+    let yields = Cell::new(0);
+    let scheduler = Scheduler::new();
+    let sleeper = scheduler.spawn(move || {
+        sleep_until(deadline);
+        Instant::now()
+    });
+    scheduler.spawn(|| {
+        for _ in 0..5 {
+            yields.set(yields.get() + 1);
+            yield_now();
+        }
+    });
+    scheduler.run();
+    assert!(sleeper.join().unwrap() >= deadline);
+    assert_eq!(yields.get(), 5);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn timeout_unwinds_a_blocked_function() {
+    use std::time::{Duration, Instant};
+
+    let drops = Cell::new(0);
+    let (tx, rx) = channel::channel::<i32>();
+    let scheduler = Scheduler::new();
+    let waiter = scheduler.spawn(|| {
+        let elapsed = timeout(Duration::from_millis(10), || {
+            let _guard = DropCounter(&drops);
+            rx.recv()
+        });
+        let received = timeout(Duration::from_secs(60), || rx.recv());
+        (elapsed, received)
+    });
+    scheduler.spawn(|| {
+        sleep(Duration::from_millis(30));
+        tx.send(7).unwrap();
+    });
+    let start = Instant::now();
+    scheduler.run();
+    // the timer of the second timeout is not waited for
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(matches!(waiter.join().unwrap(), (Err(Elapsed { .. }), Ok(Ok(7)))));
+    assert_eq!(drops.get(), 1);
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn timeout_after_a_wakeup_passes_it_on() {
+    use std::time::Duration;
+
+    let mutex = sync::Mutex::new(());
+    let scheduler = Scheduler::new();
+    scheduler.spawn(|| {
+        let guard = mutex.lock();
+        yield_now();
+        // block the thread past the deadline of the first waiter, which is
+        // handed the lock before it gets to notice
+        std::thread::sleep(Duration::from_millis(30));
+        drop(guard);
+    });
+    let first = scheduler.spawn(|| timeout(Duration::from_millis(10), || drop(mutex.lock())));
+    let second = scheduler.spawn(|| drop(mutex.lock()));
+    scheduler.run();
+    assert!(matches!(first.join().unwrap(), Err(Elapsed { .. })));
+    assert!(second.is_finished());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn timed_out_writer_lets_readers_in() {
+    use std::time::Duration;
+    let lock = sync::RwLock::new(());
+    let scheduler = Scheduler::new();
+    scheduler.spawn(|| {
+        let guard = lock.read();
+        yield_now();
+        // the writer is handed the lock past its deadline, and nobody but
+        // the reader it held back is left to take it
+        std::thread::sleep(Duration::from_millis(30));
+        drop(guard);
+    });
+    let writer = scheduler.spawn(|| timeout(Duration::from_millis(10), || drop(lock.write())));
+    let reader = scheduler.spawn(|| drop(lock.read()));
+    scheduler.run();
+    assert!(matches!(writer.join().unwrap(), Err(Elapsed { .. })));
+    assert!(reader.is_finished());
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn finished_timeouts_release_their_timers() {
+    use std::time::Duration;
+
+    let result = Rc::new(());
+    let scheduler = Scheduler::new();
+    // keeps timers expiring before that of the other task pending
+    let checker = scheduler.spawn({
+        let result = result.clone();
+        move || {
+            timeout(Duration::from_secs(30), || {
+                sleep(Duration::from_millis(10));
+                // the result of the other task went with its last reference
+                Rc::strong_count(&result)
+            })
+        }
+    });
+    drop(scheduler.spawn({
+        let result = result.clone();
+        move || {
+            timeout(Duration::from_secs(60), || ()).unwrap();
+            result
+        }
+    }));
+    scheduler.run();
+    assert_eq!(checker.join().unwrap(), Ok(2));
+}
+
+#[cfg(feature = "scheduler")]
+#[test]
+fn nested_timeouts_stop_at_their_own_deadline() {
+    use std::time::Duration;
+
+    let scheduler = Scheduler::new();
+    let task = scheduler.spawn(|| {
+        let outer = timeout(Duration::from_millis(10), || {
+            let inner = timeout(Duration::from_secs(60), || sleep(Duration::from_secs(60)));
+            unreachable!("inner timeout returned {:?}", inner)
+        });
+        let inner = timeout(Duration::from_secs(60), || {
+            timeout(Duration::from_millis(10), || sleep(Duration::from_secs(60)))
+        });
+        (outer, inner)
+    });
+    scheduler.run();
+    assert!(matches!(task.join().unwrap(), (Err(Elapsed { .. }), Ok(Err(Elapsed { .. })))));
+}
@@ -0,0 +1,103 @@
+//! Stackful coroutines, switched between by a few lines of assembly per
+//! platform: i686, x86_64 with the System V or the Windows calling
+//! convention, AArch64 and RISC-V 64 (with the D extension).
+//! Other Unix targets fall back to libc's `swapcontext`. The switch of the
+//! chosen backend is available by itself as `coroutine::RawContext`.
+//!
+//! Cargo features, all but `backend-ucontext` and `demo` on by default:
+//!
+//! - `backend-asm`: switch with the assembly of the target where there is
+//!   one.
+//! - `backend-ucontext`: switch with `swapcontext` on every target, taking
+//!   precedence over `backend-asm`.
+//! - `guard-pages`: allocate stacks with `mmap` below a guard page, and
+//!   report overflows into it. Unix only, without it stacks come from the
+//!   global allocator. Implies `std`.
+//! - `std`: keep the running coroutine per thread, and catch panics of
+//!   coroutines to hand them to `resume`. Without it the crate is `no_std`
+//!   and only needs `alloc`, but coroutines must all run on one thread, as
+//!   promised by calling the unsafe `assume_single_threaded` first, a panic
+//!   in one is not caught, and dropping a suspended one does not drop what
+//!   is alive on its stack. `backend-ucontext` implies it.
+//! - `scheduler`: `Scheduler`, running coroutines on a thread by turns, the
+//!   `coroutine::scheduler` module with `sleep` and `timeout` for its tasks,
+//!   and the channels of `coroutine::channel` and locks of `coroutine::sync`
+//!   between them. Implies `std`.
+//! - `demo`: what the demo binaries of the repository need, nothing of the
+//!   library.
+//!
+//! Every coroutine runs on a stack of its own, so it can suspend itself from
+//! any depth of calls, either with `yield_now` or with the `Yielder` handed
+//! to it, which also exchanges values with the caller of `resume`.
+//!
+//! ```
+//! use stackful_coroutine::{Coroutine, CoroutineResult, Yielder};
+//!
+//! # #[cfg(not(feature = "std"))]
+//! # unsafe { stackful_coroutine::assume_single_threaded() };
+//! let mut squares = Coroutine::with_yielder(|yielder: &Yielder<(), u32>, ()| {
+//!     for i in 1..4 {
+//!         yielder.yield_(i * i);
+//!     }
+//!     "done"
+//! });
+//! assert_eq!(squares.resume(()), CoroutineResult::Yielded(1));
+//! assert_eq!(squares.resume(()), CoroutineResult::Yielded(4));
+//! assert_eq!(squares.resume(()), CoroutineResult::Yielded(9));
+//! assert_eq!(squares.resume(()), CoroutineResult::Complete("done"));
+//! ```
+
+#![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(not(any(feature = "backend-asm", feature = "backend-ucontext")))]
+compile_error!("no context switch backend, enable the `backend-asm` or the `backend-ucontext` feature");
+
+#[cfg(all(not(unix), feature = "backend-ucontext"))]
+compile_error!("the `backend-ucontext` feature needs a Unix target, use `backend-asm` instead");
+
+#[cfg(all(
+    not(unix),
+    feature = "backend-asm",
+    not(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))
+))]
+compile_error!("`backend-asm` has no assembly for this target, and `backend-ucontext` needs Unix");
+
+#[cfg(all(
+    unix,
+    feature = "backend-asm",
+    not(feature = "std"),
+    not(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))
+))]
+compile_error!("`backend-asm` has no assembly for this target and falls back to `swapcontext`, which needs the `std` feature");
+
+#[cfg(all(
+    target_arch = "riscv64",
+    riscv64_without_d,
+    feature = "backend-asm",
+    not(feature = "backend-ucontext")
+))]
+compile_error!("the riscv64 assembly of `backend-asm` saves fs0-fs11 and needs the D extension, build for a target with it such as riscv64gc or use `backend-ucontext`");
+
+pub mod coroutine;
+
+#[cfg(not(feature = "std"))]
+pub use coroutine::assume_single_threaded;
+#[cfg(feature = "scheduler")]
+pub use coroutine::{spawn, JoinHandle, Scheduler};
+pub use coroutine::{
+    in_coroutine, yield_now, Coroutine, CoroutineBuilder, CoroutineResult, CurrentCoroutine,
+    PanicPayload, ResumeError, Stack, Transfer, Yielder,
+};
@@ -1,8 +1,7 @@
 use rand::Rng;
 
-mod coroutine;
-
-use coroutine::Coroutine;
+use stackful_coroutine::coroutine::{self, channel};
+use stackful_coroutine::{Coroutine, CoroutineResult, Scheduler, Yielder};
 
 fn func(index: i32, tag: i32) {
     for i in 0..4 {
@@ -11,29 +10,61 @@ fn func(index: i32, tag: i32) {
     }
 }
 
+// sums up the numbers sent in, yielding the running total after each one
+fn running_sum(yielder: &Yielder<i32, i32>, mut input: i32) -> i32 {
+    let mut sum = 0;
+    while input != 0 {
+        sum += input;
+        input = yielder.yield_(sum);
+    }
+    sum
+}
+
 fn main() {
-    let mut threads = Vec::new();
+    let scheduler = Scheduler::new();
 
     for index in 0..3 {
-        threads.push(Coroutine::new(move || {
+        scheduler.spawn(move || {
             let tag = rand::thread_rng().gen_range(0..100);
             for i in 0..3 {
                 println!("thread {}, tag: {}, count: {}", index, tag, i);
                 coroutine::yield_now();
             }
-        }));
+        });
     }
-    threads.push(Coroutine::new(|| {
+    scheduler.spawn(|| {
         let tag = rand::thread_rng().gen_range(0..100);
         func(3, tag);
-    }));
+    });
 
-    threads.push(Coroutine::new(|| {
+    scheduler.spawn(|| {
         for _ in 0..4 {
             println!("-----");
             coroutine::yield_now();
         }
-    }));
+    });
+
+    // the producer runs ahead of the consumer by at most one number
+    let (tx, rx) = channel::sync_channel(1);
+    scheduler.spawn(move || {
+        for i in 0..3 {
+            println!("producer sends {}", i);
+            tx.send(i).unwrap();
+        }
+    });
+    scheduler.spawn(move || {
+        for i in &rx {
+            println!("consumer receives {}", i);
+        }
+    });
+
+    scheduler.run();
 
-    coroutine::schedule(&mut threads);
+    let mut summer = Coroutine::with_yielder(running_sum);
+    for input in [3, 1, 4, 1, 5, 0] {
+        match summer.resume(input) {
+            CoroutineResult::Yielded(sum) => println!("sent {}, running sum: {}", input, sum),
+            CoroutineResult::Complete(sum) => println!("sent {}, final sum: {}", input, sum),
+        }
+    }
 }
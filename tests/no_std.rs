@@ -0,0 +1,86 @@
+// The crate built without std, where coroutines are kept track of in plain
+// statics. Run with `cargo test --no-default-features --features backend-asm`.
+//
+// A single test, so that everything runs on the one thread promised to
+// `assume_single_threaded`.
+
+#![cfg(not(feature = "std"))]
+
+use std::cell::Cell;
+use std::panic::catch_unwind;
+
+use stackful_coroutine::{
+    assume_single_threaded, in_coroutine, yield_now, Coroutine, CoroutineResult, Yielder,
+};
+
+struct DropCounter<'a>(&'a Cell<u32>);
+
+impl Drop for DropCounter<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test]
+fn coroutines_run_on_a_single_thread() {
+    let refused = catch_unwind(|| drop(Coroutine::new(|| ()))).unwrap_err();
+    assert_eq!(
+        refused.downcast_ref::<&str>(),
+        Some(&"coroutines without std need `assume_single_threaded` to be called first")
+    );
+    // only allocating a stack looks up the pool of the thread
+    let mut buffer = vec![0u8; 64 * 1024];
+    Coroutine::with_stack(&mut buffer, || ()).leak();
+    unsafe { assume_single_threaded() };
+
+    // resume and yield, with a `Yielder` and with `yield_now`
+    let mut sums = Coroutine::with_yielder(|yielder: &Yielder<i32, i32>, mut input| {
+        let mut sum = 0;
+        while input != 0 {
+            sum += input;
+            input = yielder.yield_(sum);
+        }
+        sum
+    });
+    assert_eq!(sums.resume(1), CoroutineResult::Yielded(1));
+    assert_eq!(sums.resume(2), CoroutineResult::Yielded(3));
+    assert_eq!(sums.resume(0), CoroutineResult::Complete(3));
+
+    let steps = Cell::new(0);
+    let mut coro = Coroutine::new(|| {
+        assert!(in_coroutine());
+        steps.set(1);
+        yield_now();
+        steps.set(2);
+    });
+    assert!(!in_coroutine());
+    assert_eq!(coro.resume(()), CoroutineResult::Yielded(()));
+    assert_eq!(steps.get(), 1);
+    assert_eq!(coro.resume(()), CoroutineResult::Complete(()));
+    assert_eq!(steps.get(), 2);
+
+    // dropping a suspended coroutine frees its stack without unwinding it
+    let drops = Cell::new(0);
+    let mut coro = Coroutine::new(|| {
+        let _counter = DropCounter(&drops);
+        yield_now();
+    });
+    coro.resume(());
+    drop(coro);
+    assert_eq!(drops.get(), 0);
+
+    // and so does leaking it, also before it ever ran
+    let mut coro = Coroutine::new(|| {
+        let _counter = DropCounter(&drops);
+        yield_now();
+    });
+    coro.resume(());
+    coro.leak();
+    Coroutine::new(|| DropCounter(&drops)).leak();
+    assert_eq!(drops.get(), 0);
+
+    // the thread is left as it was, ready for more
+    assert!(!in_coroutine());
+    let mut coro = Coroutine::new(|| 7);
+    assert_eq!(coro.resume(()), CoroutineResult::Complete(7));
+}